@@ -0,0 +1,262 @@
+use bevy::prelude::*;
+
+use crate::input::{Device, HorizontalSocd, VerticalSocd};
+use crate::motions::MotionWindows;
+
+// how many frames GGRS may run ahead of the confirmed inputs. A synctest
+// has to roll back fewer frames than that, and at least 2 to have any
+// checksums to compare
+pub const MAX_PREDICTION: usize = 8;
+
+// launch options. Natively they are read from the command line
+// (`--synctest 2`, `--local`, `--room friday`), in the browser from the
+// page's query string (`?synctest=2`, `?local`, `?room=friday`)
+#[derive(Resource, Debug)]
+pub struct Args {
+    // run a GGRS SyncTestSession with this check distance instead of
+    // looking for an opponent
+    pub synctest: Option<usize>,
+    // two players on one keyboard, no matchbox server needed
+    pub local: bool,
+    // address of the matchbox server, without a room
+    pub matchbox: String,
+    // meet in this named room instead of getting paired with whoever is
+    // next in line
+    pub room: Option<String>,
+    // how many peers the session waits for. Two of them fight, everyone
+    // else watches
+    pub players: usize,
+    // frames of input delay, defaults to 2 online and 0 locally
+    pub input_delay: Option<usize>,
+    // for how many frames a button press waits for the fighter to be able
+    // to act on it. This changes the simulation, so online matches always
+    // use the default
+    pub input_buffer: usize,
+    // how quickly each special move motion has to be done, in frames:
+    // `qc_window`, `dp_window`, `hcf_window`, `circle_window` and
+    // `dash_window`, plus `charge_frames` and `charge_release` for charge
+    // moves. Like the input buffer they change the simulation, so online
+    // matches use the defaults
+    pub motion_windows: MotionWindows,
+    // how left + right (`neutral` or `last`) and up + down (`up` or
+    // `neutral`) held together are resolved
+    pub socd_x: HorizontalSocd,
+    pub socd_y: VerticalSocd,
+    // what each player plays with in local versus: `keyboard` (their
+    // half of it), `gamepad` or `gamepadN` for the Nth pad from 0
+    pub devices: [Device; 2],
+    // whether jumping fighters can block. Changes the simulation, so online
+    // matches always leave it off
+    pub air_block: bool,
+    // draw the pushboxes, hitboxes and grabboxes from the start (F2
+    // toggles them during a match)
+    pub boxes: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            synctest: None,
+            local: false,
+            matchbox: "ws://127.0.0.1:3536".to_string(),
+            room: None,
+            players: 2,
+            input_delay: None,
+            input_buffer: 4,
+            motion_windows: MotionWindows::default(),
+            socd_x: HorizontalSocd::Neutral,
+            socd_y: VerticalSocd::UpPriority,
+            devices: [Device::Keyboard; 2],
+            air_block: false,
+            boxes: false,
+        }
+    }
+}
+
+impl Args {
+    pub fn get() -> Self {
+        let mut args = Args::default();
+        for (key, value) in raw_options() {
+            match key.as_str() {
+                "synctest" => args.synctest = Some(parse(&key, &value, 2)),
+                "local" => args.local = parse(&key, &value, true),
+                "matchbox" if !value.is_empty() => args.matchbox = value,
+                "room" if !value.is_empty() => args.room = Some(value),
+                "players" => args.players = parse(&key, &value, 2),
+                "input_delay" => args.input_delay = Some(parse(&key, &value, 2)),
+                "input_buffer" => args.input_buffer = parse(&key, &value, 4),
+                "qc_window" => args.motion_windows.quarter_circle = parse(&key, &value, args.motion_windows.quarter_circle),
+                "dp_window" => args.motion_windows.dragon_punch = parse(&key, &value, args.motion_windows.dragon_punch),
+                "hcf_window" => args.motion_windows.half_circle = parse(&key, &value, args.motion_windows.half_circle),
+                "circle_window" => args.motion_windows.full_circle = parse(&key, &value, args.motion_windows.full_circle),
+                "dash_window" => args.motion_windows.dash = parse(&key, &value, args.motion_windows.dash),
+                "charge_frames" => args.motion_windows.charge = parse(&key, &value, args.motion_windows.charge),
+                "charge_release" => args.motion_windows.charge_release = parse(&key, &value, args.motion_windows.charge_release),
+                "socd_x" => args.socd_x = parse(&key, &value, HorizontalSocd::Neutral),
+                "socd_y" => args.socd_y = parse(&key, &value, VerticalSocd::UpPriority),
+                "p1" => args.devices[0] = parse(&key, &value, Device::Keyboard),
+                "p2" => args.devices[1] = parse(&key, &value, Device::Keyboard),
+                "air_block" => args.air_block = parse(&key, &value, true),
+                "boxes" => args.boxes = parse(&key, &value, true),
+                _ => warn!("ignoring unknown launch option {key:?}"),
+            }
+        }
+        if let Some(distance) = args.synctest {
+            let checked = distance.clamp(2, MAX_PREDICTION - 1);
+            if checked != distance {
+                warn!("a synctest checks 2 to {} frames back, not {distance}", MAX_PREDICTION - 1);
+                args.synctest = Some(checked);
+            }
+        }
+        // Options that change the simulation would desync two peers that
+        // don't agree on them, and nothing makes them agree. They are only
+        // for local versus and synctests
+        if !args.plays_locally() {
+            let defaults = Args::default();
+            if args.input_buffer != defaults.input_buffer {
+                warn!("input_buffer only works locally, online matches use {}", defaults.input_buffer);
+                args.input_buffer = defaults.input_buffer;
+            }
+            if args.motion_windows != defaults.motion_windows {
+                warn!("motion windows only work locally, online matches use {:?}", defaults.motion_windows);
+                args.motion_windows = defaults.motion_windows;
+            }
+            if args.air_block != defaults.air_block {
+                warn!("air_block only works locally, online matches use {}", defaults.air_block);
+                args.air_block = defaults.air_block;
+            }
+        }
+        if args.players < 2 {
+            warn!("a match needs at least 2 players, not {}", args.players);
+            args.players = 2;
+        }
+        info!("launch options: {:?}", args);
+        args
+    }
+
+    // the matchbox room to join. Everyone in a named room gets connected
+    // to each other, otherwise players are paired up in arrival order
+    pub fn room_url(&self) -> String {
+        let matchbox = self.matchbox.trim_end_matches('/');
+        match &self.room {
+            Some(room) => format!("{matchbox}/extreme_bevy_{}", encode(room)),
+            None => format!("{matchbox}/extreme_bevy?next={}", self.players),
+        }
+    }
+
+    // both players are on this machine, so there is no matchmaking
+    pub fn plays_locally(&self) -> bool {
+        self.local || self.synctest.is_some()
+    }
+}
+
+// parse an option value, falling back to `default` if it is missing or
+// malformed
+fn parse<T: std::str::FromStr>(key: &str, value: &str, default: T) -> T {
+    if value.is_empty() {
+        return default;
+    }
+    value.parse().unwrap_or_else(|_| {
+        warn!("invalid value {value:?} for launch option {key:?}");
+        default
+    })
+}
+
+// percent-encode everything but letters, digits and `-_.~`, so a room
+// name can't add its own path or query to the matchbox url
+fn encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// `--key value`, `--key=value` and bare `--flag` pairs from the command line
+#[cfg(not(target_arch = "wasm32"))]
+fn raw_options() -> Vec<(String, String)> {
+    let mut options: Vec<(String, String)> = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if let Some(option) = arg.strip_prefix("--") {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            options.push((key.to_string(), value.to_string()));
+        } else if let Some((_, value)) = options.last_mut().filter(|(_, value)| value.is_empty()) {
+            *value = arg;
+        }
+    }
+    options
+}
+
+// `key=value` and bare `key` pairs from the page's query string
+#[cfg(target_arch = "wasm32")]
+fn raw_options() -> Vec<(String, String)> {
+    let search = web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .unwrap_or_default();
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+// undo the percent-encoding of a query string component, so values like
+// `matchbox=wss%3A%2F%2Fexample.com` come out as typed
+#[cfg(target_arch = "wasm32")]
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_are_encoded() {
+        let args = Args {
+            room: Some("friday night?next=2&x".to_string()),
+            ..default()
+        };
+        assert_eq!(
+            args.room_url(),
+            "ws://127.0.0.1:3536/extreme_bevy_friday%20night%3Fnext%3D2%26x"
+        );
+    }
+
+    #[test]
+    fn plain_room_names_stay_readable() {
+        let args = Args {
+            room: Some("friday-2.v2_~".to_string()),
+            ..default()
+        };
+        assert_eq!(args.room_url(), "ws://127.0.0.1:3536/extreme_bevy_friday-2.v2_~");
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::args::Args;
+use crate::input::Button;
+use crate::ui::status_text;
+use crate::{FontAssets, GameState};
+
+// the default keys that make up one player's controls
+type DefaultKeys = &'static [(Button, &'static [KeyCode])];
+
+// online there is only one player per keyboard, so they get every key
+const ONLINE_KEYS: DefaultKeys = &[
+    (Button::Up, &[KeyCode::Up, KeyCode::W]),
+    (Button::Down, &[KeyCode::Down, KeyCode::S]),
+    (Button::Left, &[KeyCode::Left, KeyCode::A]),
+    (Button::Right, &[KeyCode::Right, KeyCode::D]),
+    (Button::LightPunch, &[KeyCode::O]),
+    (Button::HeavyPunch, &[KeyCode::P]),
+    (Button::LightKick, &[KeyCode::L]),
+    (Button::HeavyKick, &[KeyCode::Semicolon]),
+    (Button::Block, &[KeyCode::K]),
+    (Button::Grab, &[KeyCode::I]),
+    (Button::Special, &[KeyCode::U]),
+    (Button::Start, &[KeyCode::Escape]),
+    (Button::Taunt, &[KeyCode::T]),
+];
+
+// in local versus the keyboard is split in two: player 1 on the left
+// (WASD + R T F G), player 2 on the right (arrows + numpad 4 5 1 2)
+const LOCAL_KEYS: [DefaultKeys; 2] = [
+    &[
+        (Button::Up, &[KeyCode::W]),
+        (Button::Down, &[KeyCode::S]),
+        (Button::Left, &[KeyCode::A]),
+        (Button::Right, &[KeyCode::D]),
+        (Button::LightPunch, &[KeyCode::R]),
+        (Button::HeavyPunch, &[KeyCode::T]),
+        (Button::LightKick, &[KeyCode::F]),
+        (Button::HeavyKick, &[KeyCode::G]),
+        (Button::Block, &[KeyCode::V]),
+        (Button::Grab, &[KeyCode::E]),
+        (Button::Special, &[KeyCode::Y]),
+        (Button::Start, &[KeyCode::Key1]),
+        (Button::Taunt, &[KeyCode::Key2]),
+    ],
+    &[
+        (Button::Up, &[KeyCode::Up]),
+        (Button::Down, &[KeyCode::Down]),
+        (Button::Left, &[KeyCode::Left]),
+        (Button::Right, &[KeyCode::Right]),
+        (Button::LightPunch, &[KeyCode::Numpad4]),
+        (Button::HeavyPunch, &[KeyCode::Numpad5]),
+        (Button::LightKick, &[KeyCode::Numpad1]),
+        (Button::HeavyKick, &[KeyCode::Numpad2]),
+        (Button::Block, &[KeyCode::Numpad0]),
+        (Button::Grab, &[KeyCode::Numpad6]),
+        (Button::Special, &[KeyCode::Numpad3]),
+        (Button::Start, &[KeyCode::NumpadEnter]),
+        (Button::Taunt, &[KeyCode::Numpad9]),
+    ],
+];
+
+// The default gamepad layout, made for 6-button fight sticks (and pads
+// that report the same buttons): punches on the top row, kicks on the
+// bottom row, block and grab on the last column
+const GAMEPAD_BUTTONS: &[(Button, &[GamepadButtonType])] = &[
+    (Button::Up, &[GamepadButtonType::DPadUp]),
+    (Button::Down, &[GamepadButtonType::DPadDown]),
+    (Button::Left, &[GamepadButtonType::DPadLeft]),
+    (Button::Right, &[GamepadButtonType::DPadRight]),
+    (Button::LightPunch, &[GamepadButtonType::West]),
+    (Button::HeavyPunch, &[GamepadButtonType::North]),
+    (Button::LightKick, &[GamepadButtonType::South]),
+    (Button::HeavyKick, &[GamepadButtonType::East]),
+    (Button::Block, &[GamepadButtonType::RightTrigger]),
+    (Button::Grab, &[GamepadButtonType::RightTrigger2]),
+    (Button::Special, &[GamepadButtonType::LeftTrigger]),
+    (Button::Start, &[GamepadButtonType::Start]),
+    (Button::Taunt, &[GamepadButtonType::Select]),
+];
+
+// how far the stick has to be pushed before it counts as a direction
+const STICK_DEADZONE: f32 = 0.4;
+
+// the keys bound to each button of one player
+pub type KeySet = BTreeMap<Button, Vec<KeyCode>>;
+
+fn default_gamepad() -> BTreeMap<Button, Vec<GamepadButtonType>> {
+    GAMEPAD_BUTTONS
+        .iter()
+        .map(|&(button, codes)| (button, codes.to_vec()))
+        .collect()
+}
+
+fn default_stick_deadzone() -> f32 {
+    STICK_DEADZONE
+}
+
+fn key_set(defaults: DefaultKeys) -> KeySet {
+    defaults
+        .iter()
+        .map(|&(button, codes)| (button, codes.to_vec()))
+        .collect()
+}
+
+// Which keys press which buttons: one key set for playing online, and
+// one per player for local versus, plus one layout shared by all
+// gamepads. Saved whenever the rebinding screen is closed, and loaded
+// again on the next start
+#[derive(Resource, Serialize, Deserialize)]
+pub struct Bindings {
+    pub online: KeySet,
+    pub local: [KeySet; 2],
+    #[serde(default = "default_gamepad")]
+    pub gamepad: BTreeMap<Button, Vec<GamepadButtonType>>,
+    #[serde(default = "default_stick_deadzone")]
+    pub stick_deadzone: f32,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            online: key_set(ONLINE_KEYS),
+            local: [key_set(LOCAL_KEYS[0]), key_set(LOCAL_KEYS[1])],
+            gamepad: default_gamepad(),
+            stick_deadzone: STICK_DEADZONE,
+        }
+    }
+}
+
+impl Bindings {
+    // the saved bindings, or the defaults if there are none (or they
+    // can't be read). Buttons added since the file was saved get their
+    // default keys
+    pub fn load() -> Self {
+        let Some(saved) = read_saved() else {
+            return Self::default();
+        };
+        let mut bindings: Bindings = match ron::from_str(&saved) {
+            Ok(bindings) => bindings,
+            Err(e) => {
+                warn!("ignoring unreadable key bindings: {e}");
+                return Self::default();
+            }
+        };
+        let defaults = Self::default();
+        for (keys, default_keys) in bindings.sets_mut().into_iter().zip(defaults.sets()) {
+            for (button, codes) in default_keys {
+                keys.entry(*button).or_insert_with(|| codes.clone());
+            }
+        }
+        for (button, codes) in defaults.gamepad {
+            bindings.gamepad.entry(button).or_insert(codes);
+        }
+        bindings
+    }
+
+    pub fn save(&self) {
+        match ron::ser::to_string_pretty(self, default()) {
+            Ok(bindings) => write_saved(&bindings),
+            Err(e) => warn!("could not save key bindings: {e}"),
+        }
+    }
+
+    // the key sets in the order the rebinding screen shows them
+    fn sets(&self) -> [&KeySet; 3] {
+        [&self.online, &self.local[0], &self.local[1]]
+    }
+
+    fn sets_mut(&mut self) -> [&mut KeySet; 3] {
+        let [player1, player2] = &mut self.local;
+        [&mut self.online, player1, player2]
+    }
+}
+
+const SET_NAMES: [&str; 3] = ["Online", "Local player 1", "Local player 2"];
+
+// natively the bindings live in a file next to the game
+#[cfg(not(target_arch = "wasm32"))]
+const BINDINGS_FILE: &str = "bindings.ron";
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_saved() -> Option<String> {
+    std::fs::read_to_string(BINDINGS_FILE).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_saved(bindings: &str) {
+    if let Err(e) = std::fs::write(BINDINGS_FILE, bindings) {
+        warn!("could not save key bindings to {BINDINGS_FILE}: {e}");
+    }
+}
+
+// in the browser they go to the page's local storage
+#[cfg(target_arch = "wasm32")]
+const BINDINGS_KEY: &str = "extreme_bevy_bindings";
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+#[cfg(target_arch = "wasm32")]
+fn read_saved() -> Option<String> {
+    local_storage()?.get_item(BINDINGS_KEY).ok()?
+}
+
+#[cfg(target_arch = "wasm32")]
+fn write_saved(bindings: &str) {
+    let saved = local_storage().map(|storage| storage.set_item(BINDINGS_KEY, bindings));
+    if !matches!(saved, Some(Ok(()))) {
+        warn!("could not save key bindings to local storage");
+    }
+}
+
+// where the rebinding screen is at: which key set and button are
+// selected, and whether it is waiting for the new key
+#[derive(Resource, Default)]
+pub struct RebindMenu {
+    set: usize,
+    selected: usize,
+    capturing: bool,
+}
+
+// marks the text of the rebinding screen
+#[derive(Component)]
+pub struct RebindText;
+
+// F1 opens the rebinding screen on top of whatever is going on. Online
+// matches keep running without us, so there it only works before and
+// after a match
+pub fn open_rebind_menu(
+    keys: Res<Input<KeyCode>>,
+    args: Res<Args>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let available = match state.current() {
+        GameState::Matchmaking | GameState::Results => true,
+        GameState::InGame => args.plays_locally(),
+        _ => false,
+    };
+    if available && keys.just_pressed(KeyCode::F1) {
+        state.push(GameState::Rebinding).unwrap();
+    }
+}
+
+pub fn show_rebind_menu(mut commands: Commands, fonts: Res<FontAssets>, mut menu: ResMut<RebindMenu>) {
+    *menu = RebindMenu::default();
+    commands.spawn((status_text(&fonts, "", Color::WHITE, 10.), RebindText));
+}
+
+// Tab switches between the key sets, up / down pick a button, Enter
+// waits for the key to bind to it, Delete goes back to its default keys
+// and Escape leaves
+pub fn update_rebind_menu(
+    mut keys: ResMut<Input<KeyCode>>,
+    mut menu: ResMut<RebindMenu>,
+    mut bindings: ResMut<Bindings>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let buttons: Vec<Button> = bindings.sets()[menu.set].keys().copied().collect();
+    let button = buttons[menu.selected];
+
+    if menu.capturing {
+        if keys.just_pressed(KeyCode::Escape) {
+            menu.capturing = false;
+        } else if let Some(&code) = keys.get_just_pressed().next() {
+            bindings.sets_mut()[menu.set].insert(button, vec![code]);
+            menu.capturing = false;
+        }
+        // the key is taken, nothing else should react to it
+        keys.clear();
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Escape) {
+        state.pop().unwrap();
+    } else if keys.just_pressed(KeyCode::Tab) {
+        menu.set = (menu.set + 1) % SET_NAMES.len();
+    } else if keys.just_pressed(KeyCode::Up) {
+        menu.selected = (menu.selected + buttons.len() - 1) % buttons.len();
+    } else if keys.just_pressed(KeyCode::Down) {
+        menu.selected = (menu.selected + 1) % buttons.len();
+    } else if keys.just_pressed(KeyCode::Return) {
+        menu.capturing = true;
+    } else if keys.just_pressed(KeyCode::Delete) {
+        let defaults = Bindings::default();
+        bindings.sets_mut()[menu.set].insert(button, defaults.sets()[menu.set][&button].clone());
+    }
+    keys.clear();
+}
+
+pub fn draw_rebind_menu(
+    menu: Res<RebindMenu>,
+    bindings: Res<Bindings>,
+    mut query: Query<&mut Text, With<RebindText>>,
+) {
+    let mut lines = vec![
+        format!("{} controls  (Tab: next player, Esc: done)", SET_NAMES[menu.set]),
+        String::new(),
+    ];
+    for (i, (button, codes)) in bindings.sets()[menu.set].iter().enumerate() {
+        let keys = if menu.capturing && i == menu.selected {
+            "press a key...".to_string()
+        } else {
+            codes.iter().map(|code| format!("{code:?}")).collect::<Vec<_>>().join(", ")
+        };
+        let cursor = if i == menu.selected { ">" } else { " " };
+        lines.push(format!("{cursor} {button:?}: {keys}"));
+    }
+
+    let menu_text = lines.join("\n");
+    for mut text in query.iter_mut() {
+        if text.sections[0].value != menu_text {
+            text.sections[0].value = menu_text.clone();
+        }
+    }
+}
+
+pub fn hide_rebind_menu(
+    mut commands: Commands,
+    bindings: Res<Bindings>,
+    query: Query<Entity, With<RebindText>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    bindings.save();
+}
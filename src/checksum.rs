@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy_ggrs::ggrs::{Message, NonBlockingSocket};
+use bevy_ggrs::Session;
+use matchbox_socket::WebRtcSocket;
+
+use crate::components::*;
+use crate::physics::{Body, Position};
+use crate::ui::status_text;
+use crate::{FontAssets, GgrsConfig};
+
+// how many frames of local checksums we keep around to compare against
+// reports that arrive late. GGRS confirms frames long before they get
+// this old
+const HISTORY_FRAMES: i32 = 600;
+
+// the first byte of every packet tells GGRS traffic and checksum reports apart
+const GGRS_PACKET: u8 = 0;
+const CHECKSUM_PACKET: u8 = 1;
+
+// counts simulation frames. Registered for rollback, so it goes back to
+// the loaded frame whenever GGRS rolls back
+#[derive(Resource, Reflect, Default, Hash)]
+#[reflect(Hash)]
+pub struct FrameCount(pub i32);
+
+// the checksum of the game state at the end of the last simulated frame.
+// Registered for rollback as well, so a SyncTestSession compares it too
+#[derive(Resource, Reflect, Default, Hash)]
+#[reflect(Hash)]
+pub struct FrameChecksum(pub u64);
+
+// 64 bit FNV-1a. Unlike the std hashers its output is specified, and
+// usizes are hashed as 64 bits, so a browser and a native peer hash the
+// same state to the same value
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_isize(&mut self, value: isize) {
+        self.write_i64(value as i64);
+    }
+}
+
+type HashedComponents<'a> = (
+    &'a SpawnId,
+    Option<&'a Position>,
+    Option<&'a BulletReady>,
+    Option<&'a MoveDir>,
+    Option<&'a Player>,
+    Option<&'a FighterState>,
+    Option<&'a Controls>,
+    Option<&'a Jumper>,
+    Option<&'a Body>,
+    Option<&'a Hitbox>,
+    Option<&'a Grabbox>,
+);
+
+// hashes every rollback entity in spawn order at the end of each
+// simulated frame, and remembers the result for the desync detection
+pub fn compute_checksum(
+    mut frame_count: ResMut<FrameCount>,
+    mut checksum: ResMut<FrameChecksum>,
+    mut history: ResMut<ChecksumHistory>,
+    query: Query<HashedComponents>,
+) {
+    frame_count.0 += 1;
+
+    let mut entities: Vec<_> = query.iter().collect();
+    entities.sort_by_key(|(id, ..)| **id);
+
+    let mut hasher = Fnv::default();
+    for (id, position, bullet_ready, move_dir, player, fighter, controls, jumper, body, hitbox, grabbox) in entities {
+        id.hash(&mut hasher);
+        // the simulation only works with fixed-point numbers, so their
+        // integers can be hashed as they are. Transforms only follow the
+        // positions for drawing and are left out
+        if let Some(position) = position {
+            position.0.hash(&mut hasher);
+        }
+        if let Some(bullet_ready) = bullet_ready {
+            bullet_ready.hash(&mut hasher);
+        }
+        if let Some(move_dir) = move_dir {
+            move_dir.0.hash(&mut hasher);
+        }
+        if let Some(player) = player {
+            player.handle.hash(&mut hasher);
+            player.hp.hash(&mut hasher);
+        }
+        if let Some(fighter) = fighter {
+            fighter.hash(&mut hasher);
+        }
+        if let Some(controls) = controls {
+            controls.hash(&mut hasher);
+        }
+        if let Some(jumper) = jumper {
+            jumper.is_jumping.hash(&mut hasher);
+        }
+        if let Some(body) = body {
+            body.velocity.hash(&mut hasher);
+            body.grounded.hash(&mut hasher);
+        }
+        // what a box does on contact is fixed when it is spawned, so only
+        // whose it is, where it is and how long it stays out change
+        if let Some(hitbox) = hitbox {
+            hitbox.owner.hash(&mut hasher);
+            hitbox.position.hash(&mut hasher);
+            hitbox.active.hash(&mut hasher);
+            hitbox.connected.hash(&mut hasher);
+        }
+        if let Some(grabbox) = grabbox {
+            grabbox.owner.hash(&mut hasher);
+            grabbox.position.hash(&mut hasher);
+            grabbox.active.hash(&mut hasher);
+        }
+    }
+
+    checksum.0 = hasher.finish();
+    // a resimulated frame simply overwrites the checksum it had before
+    history.local.insert(frame_count.0, checksum.0);
+    // forget checksums nobody is going to ask about anymore. Sessions
+    // without peers never ask, but still shouldn't pile them up
+    history.local = history.local.split_off(&(frame_count.0 - HISTORY_FRAMES));
+}
+
+// Wraps the matchbox socket so it can be shared between GGRS, which
+// needs to own a socket, and the desync detection, which sends its own
+// checksum reports to the other peers over the same connection.
+#[derive(Resource, Clone)]
+pub struct SharedSocket {
+    socket: Arc<Mutex<WebRtcSocket>>,
+    reports: Arc<Mutex<Vec<(String, i32, u64)>>>,
+}
+
+impl SharedSocket {
+    pub fn new(socket: WebRtcSocket) -> Self {
+        Self {
+            socket: Arc::new(Mutex::new(socket)),
+            reports: Default::default(),
+        }
+    }
+
+    // send our checksums of the given frames to every peer
+    fn send_report(&self, checksums: &[(i32, u64)]) {
+        let mut packet = vec![CHECKSUM_PACKET];
+        for (frame, checksum) in checksums {
+            packet.extend(frame.to_le_bytes());
+            packet.extend(checksum.to_le_bytes());
+        }
+        let mut socket = self.socket.lock().unwrap();
+        for peer in socket.connected_peers() {
+            socket.send(packet.clone().into_boxed_slice(), peer);
+        }
+    }
+
+    // the (peer, frame, checksum) reports received since the last call
+    fn take_reports(&self) -> Vec<(String, i32, u64)> {
+        std::mem::take(&mut *self.reports.lock().unwrap())
+    }
+}
+
+impl NonBlockingSocket<String> for SharedSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        let mut packet = vec![GGRS_PACKET];
+        bincode::serialize_into(&mut packet, msg).unwrap();
+        self.socket.lock().unwrap().send(packet.into_boxed_slice(), addr);
+    }
+
+    // GGRS polls the socket every frame, so this is also where the
+    // checksum reports get picked up
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        let mut messages = vec![];
+        let packets = self.socket.lock().unwrap().receive();
+        for (peer, packet) in packets {
+            match packet.split_first() {
+                Some((&GGRS_PACKET, msg)) => match bincode::deserialize(msg) {
+                    Ok(msg) => messages.push((peer, msg)),
+                    Err(e) => warn!("dropping malformed packet from {peer}: {e}"),
+                },
+                Some((&CHECKSUM_PACKET, report)) => {
+                    let mut reports = self.reports.lock().unwrap();
+                    for entry in report.chunks_exact(12) {
+                        let frame = i32::from_le_bytes(entry[..4].try_into().unwrap());
+                        let checksum = u64::from_le_bytes(entry[4..].try_into().unwrap());
+                        reports.push((peer.clone(), frame, checksum));
+                    }
+                }
+                _ => warn!("dropping unknown packet from {peer}"),
+            }
+        }
+        messages
+    }
+}
+
+// local checksums by frame, reports from the other peers that we can't
+// check yet, and the first frame the peers were found to diverge on
+#[derive(Resource, Default)]
+pub struct ChecksumHistory {
+    local: BTreeMap<i32, u64>,
+    remote: Vec<(String, i32, u64)>,
+    last_sent: i32,
+    pub desync: Option<i32>,
+}
+
+// marks the desync warning text
+#[derive(Component)]
+pub struct DesyncText;
+
+// Once GGRS has confirmed a frame on this peer its checksum can't change
+// anymore, so we send it to the other peers, and compare the checksums
+// they sent us for frames we have confirmed ourselves. The first mismatch
+// is logged and shown on screen.
+pub fn detect_desyncs(
+    mut commands: Commands,
+    session: Option<Res<Session<GgrsConfig>>>,
+    socket: Option<Res<SharedSocket>>,
+    mut history: ResMut<ChecksumHistory>,
+    fonts: Res<FontAssets>,
+) {
+    let Some(socket) = socket else {
+        return;
+    };
+    let Some(Session::P2PSession(session)) = session.as_deref() else {
+        // spectators only watch, they don't take part in the comparison
+        socket.take_reports();
+        return;
+    };
+    // the state after advancing frame n is the one counted as frame n + 1
+    let confirmed = session.confirmed_frame() + 1;
+
+    // nothing is confirmed before the first frame, and most render frames
+    // don't confirm a new one
+    if confirmed > history.last_sent {
+        let newly_confirmed: Vec<_> = history
+            .local
+            .range(history.last_sent + 1..=confirmed)
+            .map(|(&frame, &checksum)| (frame, checksum))
+            .collect();
+        if let Some(&(frame, _)) = newly_confirmed.last() {
+            socket.send_report(&newly_confirmed);
+            history.last_sent = frame;
+        }
+    }
+
+    history.remote.extend(socket.take_reports());
+    let remote = std::mem::take(&mut history.remote);
+    for (peer, frame, checksum) in remote {
+        if frame > confirmed {
+            // we might still resimulate this frame, check it later
+            history.remote.push((peer, frame, checksum));
+            continue;
+        }
+        let Some(&local) = history.local.get(&frame) else {
+            continue; // too old to compare
+        };
+        if local != checksum && history.desync.is_none() {
+            warn!("desync with peer {peer} on frame {frame}: our checksum is {local:016x}, theirs is {checksum:016x}");
+            commands.spawn((
+                status_text(&fonts, format!("Desync detected on frame {frame}"), Color::RED, 10.),
+                DesyncText,
+            ));
+            history.desync = Some(frame);
+        }
+    }
+}
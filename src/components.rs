@@ -1,80 +1,149 @@
 use bevy::prelude::*;
-use bevy::sprite::ColorMaterial;
+use serde::Deserialize;
 
-pub struct Materials {
-    pub player_material: Handle<ColorMaterial>,
-    pub floor_material: Handle<ColorMaterial>,
-    pub hitbox_material: Handle<ColorMaterial>,
+use crate::fixed::*;
+use crate::input::PlayerInput;
+
+// Tells rollback entities apart in the order they were spawned. bevy_ggrs'
+// own Rollback ids come from a RollbackIdProvider that isn't rolled back,
+// so a box spawned again while resimulating a frame gets a new one. These
+// come from SpawnCounter, which is, so they are the same every time the
+// frame is simulated, on every peer
+#[derive(Component, Reflect, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct SpawnId(pub u32);
+
+#[derive(Resource, Reflect, Default, Hash)]
+#[reflect(Hash)]
+pub struct SpawnCounter(pub u32);
+
+impl SpawnCounter {
+    pub fn next(&mut self) -> SpawnId {
+        self.0 += 1;
+        SpawnId(self.0)
+    }
 }
 
 // platformer additions
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Reflect, Default)]
 pub enum GameDirection {
     Left,
+    #[default]
     Right,
 }
 
-// adds the Player marker component
-#[derive(Component)]
+// adds the Player marker component / registered for rollback so that
+// damage taken during a mispredicted frame gets undone again
+#[derive(Component, Reflect, Default)]
 pub struct Player {
     pub handle: usize,
 
     // platformer additions
-    pub speed: f32,
     pub facing_direction: GameDirection,
-    pub hp: f32,
+    pub hp: Fixed,
 }
 
-#[derive(Component)]
+// how many frames of input every player keeps around
+pub const INPUT_HISTORY: usize = 32;
+
+// The input a player's simulation works with: a ring buffer of the last
+// INPUT_HISTORY frames of held buttons, and the presses among them that
+// haven't been acted on yet. Presses and releases are found inside the
+// rollback schedule this way instead of depending on render frames, and
+// a press can wait a few frames for the fighter to be able to use it.
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Hash)]
+pub struct Controls {
+    pub held: [PlayerInput; INPUT_HISTORY],
+    pub presses: [PlayerInput; INPUT_HISTORY],
+    // index of the current frame in both buffers
+    pub head: usize,
+}
+
+// jumping ability of a fighter, registered for rollback so a jump picks
+// up where it was after a resimulation. `jump_impulse` is the upward
+// speed (in units per frame) a jump starts with, the rest of the arc is
+// up to the fighter's physics::Body
+#[derive(Component, Reflect, Default)]
 pub struct Jumper {
-    pub jump_impulse: f32,
+    pub jump_impulse: Fixed,
     pub is_jumping: bool,
 }
 
-#[derive(Component)]
-pub struct PlayerStates {
-    pub Idle: f32,
-    pub Walk: f32,
-    pub WalkB: f32,
-    pub Crouch: f32,
-    pub Jump: f32,
-    pub Dash: f32,
-    pub DashB: f32,
-    pub Block: f32,
-    pub BlockC: f32,
-    pub Hitstun: f32,
-    pub HitstunC: f32,
-    pub HitstunA: f32,
-    pub Launch: f32,
-    pub Falldown: f32,
-    pub Knockdown: f32,
-    pub Wallbounce: f32,
-    pub Wakeup: f32,
-    pub WakeupQ: f32,
-    pub Defeat: f32,
-    pub PunchL: f32,
-    pub PunchH: f32,
-    pub KickL: f32,
-    pub KickH: f32,
-    pub PunchLC: f32,
-    pub PunchHC: f32,
-    pub KickLC: f32,
-    pub KickHC: f32,
-    pub PunchLA: f32,
-    pub PunchHA: f32,
-    pub KickLA: f32,
-    pub KickHA: f32,
-    pub Command: f32,
-    pub Grab: f32,
-    pub ThrowF: f32,
-    pub ThrowB: f32,
-    pub Special: f32,
+// every state a fighter can be in. A player is always in exactly one of
+// them, and the names double as the keys for per-move data
+// (C = crouching, A = air, B = backwards, Q = quick, F = forward)
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+pub enum PlayerState {
+    #[default]
+    Idle,
+    Walk,
+    WalkB,
+    Crouch,
+    PreJump, // the few frames before leaving the ground
+    Jump,
+    Landing,
+    Dash,
+    DashB,
+    Block,
+    BlockC,
+    Hitstun,
+    HitstunC,
+    HitstunA,
+    Launch,
+    Falldown,
+    Knockdown,
+    Wallbounce,
+    Wakeup,
+    WakeupQ,
+    Defeat,
+    PunchL,
+    PunchH,
+    KickL,
+    KickH,
+    PunchLC,
+    PunchHC,
+    KickLC,
+    KickHC,
+    PunchLA,
+    PunchHA,
+    KickLA,
+    KickHA,
+    Command,
+    Grab,
+    ThrowF,
+    ThrowB,
+    Special,
+    Thrown, // held by the other fighter's throw
+}
+
+// the per-player fighter state machine, registered for rollback (and
+// hashed, so a SyncTestSession notices when it diverges). `frame`
+// counts the simulation frames spent in the current state, `stun` is the
+// length of the current hitstun / blockstun, and `air_frames` is how long
+// the fighter has been off the ground (0 while grounded). `charge` counts
+// the frames back has been held, `charge_window` how much longer a full
+// charge can still be released (see motions.rs). During a throw
+// `partner` is the handle of the other fighter in it. `last_connected` is
+// the activation (see Hitbox) of this fighter's last move that hit or was
+// blocked
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Hash)]
+pub struct FighterState {
+    pub state: PlayerState,
+    pub frame: u32,
+    pub stun: u32,
+    pub air_frames: u32,
+    pub charge: u32,
+    pub charge_window: u32,
+    pub partner: usize,
+    pub last_connected: Option<i32>,
 }
 
 // add a new component that keeps track of whether a bullet is ready 
 // to be fired. This way we can extend it to also handle other kinds 
 // of cooldowns if necessary
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Hash)]
+#[reflect(Hash)]
 pub struct BulletReady(pub bool);
 
 // before we can write the system, we need to know which components 
@@ -88,26 +157,69 @@ pub struct Bullet;
 // will use sprites for representing player direction, we won’t 
 // actually rotate the players themselves, but what we’ll do is 
 // instead keep track of the player direction in a special MoveDir 
-// component. It will be a newtype of Vec2.
+// component. It will be a newtype of FixedVec2.
 #[derive(Component, Reflect, Default, Clone, Copy)]
-pub struct MoveDir(pub Vec2);
+pub struct MoveDir(pub FixedVec2);
 
-#[derive(Component, Default)]
+// Where an attack hits, which decides how it can be guarded. Highs and
+// mids can be blocked standing or crouching (and highs go over crouching
+// fighters entirely), lows have to be blocked crouching and overheads
+// standing
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub enum HitLevel {
+    High,
+    #[default]
+    Mid,
+    Low,
+    Overhead,
+}
+
+// an attacking rectangle. `owner` is the handle of the player that
+// threw it out, `offset` is where it sits relative to that player (already
+// mirrored for the way they were facing) and `active` is how many more
+// frames it stays out. `pushback` moves the defender away on contact and
+// `level` says how it can be guarded, `launches` whether a hit knocks the
+// defender into the air. `activation` is the frame the move that threw it
+// out was started on, shared by all boxes of that move, so a move with
+// several boxes still only connects once. `connected` is set once it has hit or been blocked
+// so a hitbox that stays active for several frames only connects once
+#[derive(Component, Reflect, Default)]
 pub struct Hitbox{
-    pub width: f32,
-    pub height: f32,
-    pub position: Vec2,
-    pub damage: f32,
-    pub chip: f32,
-    pub hitstun: i32,
-    pub blockstun: i32,
+    pub width: Fixed,
+    pub height: Fixed,
+    pub position: FixedVec2,
+    pub damage: Fixed,
+    pub chip: Fixed,
+    pub hitstun: u32,
+    pub blockstun: u32,
+    pub pushback: Fixed,
+    pub level: HitLevel,
+    pub launches: bool,
     pub visibility: Visibility,
+    pub owner: usize,
+    pub offset: FixedVec2,
+    pub active: u32,
+    pub activation: i32,
+    pub connected: bool,
 }
 
-#[derive(Component, Default)]
+// the vulnerable rectangle of a player, centered on the player's
+// position plus `offset`
+#[derive(Component, Reflect, Default)]
+pub struct Hurtbox{
+    pub width: Fixed,
+    pub height: Fixed,
+    pub offset: FixedVec2,
+}
+
+// same as Hitbox, but for grabs
+#[derive(Component, Reflect, Default)]
 pub struct Grabbox{
-    pub width: f32,
-    pub height: f32,
-    pub position: Vec2,
+    pub width: Fixed,
+    pub height: Fixed,
+    pub position: FixedVec2,
     pub visibility: Visibility,
+    pub owner: usize,
+    pub offset: FixedVec2,
+    pub active: u32,
 }
\ No newline at end of file
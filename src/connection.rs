@@ -0,0 +1,149 @@
+use bevy::prelude::*;
+use bevy_ggrs::ggrs::GGRSEvent;
+use bevy_ggrs::{Rollback, Session};
+
+use crate::checksum::*;
+use crate::components::SpawnCounter;
+use crate::ui::status_text;
+use crate::{FontAssets, GameState, GgrsConfig};
+
+// what we currently know about the connection to the other peers, as
+// reported by the GGRS session events
+#[derive(Resource, Default)]
+pub struct ConnectionStatus {
+    // (count, total) synchronization roundtrips done with the slowest peer
+    synchronizing: Option<(u32, u32)>,
+    // when (in seconds since startup) an interrupted peer gets dropped
+    interrupted: Option<f64>,
+}
+
+// why the last match ended, shown on the results screen
+#[derive(Resource)]
+pub struct MatchResult(pub String);
+
+// marks the connection status text shown during a match
+#[derive(Component)]
+pub struct ConnectionOverlay;
+
+// marks the text of the results screen
+#[derive(Component)]
+pub struct ResultsText;
+
+pub fn spawn_connection_overlay(mut commands: Commands, fonts: Res<FontAssets>) {
+    commands.spawn((status_text(&fonts, "", Color::YELLOW, 50.), ConnectionOverlay));
+}
+
+// GGRS queues up events about the other peers while it polls them. They
+// have to be drained every frame, and this is where we react to them
+pub fn handle_session_events(
+    mut commands: Commands,
+    mut session: Option<ResMut<Session<GgrsConfig>>>,
+    mut status: ResMut<ConnectionStatus>,
+    mut state: ResMut<State<GameState>>,
+    time: Res<Time>,
+) {
+    let events: Vec<_> = match session.as_deref_mut() {
+        Some(Session::P2PSession(session)) => session.events().collect(),
+        // a spectator can lose the host it watches
+        Some(Session::SpectatorSession(session)) => session.events().collect(),
+        // local sessions don't have any peers to lose
+        _ => return,
+    };
+
+    for event in events {
+        match event {
+            GGRSEvent::Synchronizing { addr, total, count } => {
+                info!("synchronizing with {addr}: {count}/{total}");
+                status.synchronizing = Some((count, total));
+            }
+            GGRSEvent::Synchronized { addr } => {
+                info!("synchronized with {addr}");
+                status.synchronizing = None;
+            }
+            GGRSEvent::NetworkInterrupted { addr, disconnect_timeout } => {
+                warn!("connection to {addr} interrupted, disconnecting in {disconnect_timeout}ms");
+                status.interrupted = Some(time.elapsed_seconds_f64() + disconnect_timeout as f64 / 1000.);
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                info!("connection to {addr} resumed");
+                status.interrupted = None;
+            }
+            // bevy_ggrs already runs the simulation 10% slower for as long
+            // as we are ahead of the other peers, which spreads the
+            // recommended wait over the next frames instead of freezing
+            GGRSEvent::WaitRecommendation { skip_frames } => {
+                debug!("{skip_frames} frames ahead of the other peers, slowing down");
+            }
+            GGRSEvent::Disconnected { addr } => {
+                warn!("{addr} disconnected, ending the match");
+                commands.insert_resource(MatchResult(format!("{addr} left the match")));
+                state.set(GameState::Results).unwrap();
+                // anything after this is about a match we are leaving anyway
+                return;
+            }
+        }
+    }
+}
+
+// keeps the overlay text in sync with the connection status, counting
+// down to the disconnect while a peer is unreachable
+pub fn update_connection_overlay(
+    status: Res<ConnectionStatus>,
+    time: Res<Time>,
+    mut query: Query<&mut Text, With<ConnectionOverlay>>,
+) {
+    let message = if let Some(deadline) = status.interrupted {
+        let remaining = (deadline - time.elapsed_seconds_f64()).max(0.);
+        format!("Connection interrupted, disconnecting in {remaining:.1}s")
+    } else if let Some((count, total)) = status.synchronizing {
+        format!("Synchronizing with opponent... {count}/{total}")
+    } else {
+        String::new()
+    };
+
+    for mut text in query.iter_mut() {
+        if text.sections[0].value != message {
+            text.sections[0].value = message.clone();
+        }
+    }
+}
+
+// everything that belongs to a single match
+type MatchEntities = Or<(With<Rollback>, With<ConnectionOverlay>, With<DesyncText>)>;
+
+// tears the match down, so the next one starts from scratch: without a
+// session resource bevy_ggrs also drops its snapshots
+pub fn leave_match(mut commands: Commands, query: Query<Entity, MatchEntities>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<Session<GgrsConfig>>();
+    commands.remove_resource::<SharedSocket>();
+    commands.insert_resource(SpawnCounter::default());
+    commands.insert_resource(FrameCount::default());
+    commands.insert_resource(FrameChecksum::default());
+    commands.insert_resource(ChecksumHistory::default());
+    commands.insert_resource(ConnectionStatus::default());
+}
+
+pub fn show_results(mut commands: Commands, fonts: Res<FontAssets>, result: Option<Res<MatchResult>>) {
+    let reason = result.map_or("The match is over".to_string(), |result| result.0.clone());
+    commands.spawn((status_text(&fonts, reason, Color::WHITE, 10.), ResultsText));
+    commands.spawn((
+        status_text(&fonts, "Press Enter to look for a new match", Color::WHITE, 50.),
+        ResultsText,
+    ));
+}
+
+pub fn leave_results(keys: Res<Input<KeyCode>>, mut state: ResMut<State<GameState>>) {
+    if keys.just_pressed(KeyCode::Return) {
+        state.set(GameState::Matchmaking).unwrap();
+    }
+}
+
+pub fn hide_results(mut commands: Commands, query: Query<Entity, With<ResultsText>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<MatchResult>();
+}
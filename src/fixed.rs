@@ -0,0 +1,252 @@
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub, SubAssign};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Deserializer};
+
+// how many steps a Fixed has per unit
+const SCALE: i64 = 1000;
+
+// A number with three fixed decimals, stored as an integer count of
+// thousandths. Integer math gives bit-identical results on every CPU and
+// in every browser, which f32 doesn't promise once the compiler and the
+// platform get a say, so everything the simulation keeps (positions,
+// speeds, hp, box sizes) uses these. They only turn into floats to be
+// drawn (see physics::sync_transforms)
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(SCALE as i32);
+
+    pub const fn from_int(value: i32) -> Self {
+        Fixed(value * SCALE as i32)
+    }
+
+    // `value` thousandths, so 0.13 is written Fixed::milli(130)
+    pub const fn milli(value: i32) -> Self {
+        Fixed(value)
+    }
+
+    // for drawing only, never feed the result back into the simulation
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / SCALE as f32
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.abs())
+    }
+
+    // -1, 0 or +1
+    pub fn signum(self) -> i32 {
+        self.0.signum()
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+// products and quotients round toward zero, the same on every platform
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed((self.0 as i64 * other.0 as i64 / SCALE) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed((self.0 as i64 * SCALE / other.0 as i64) as i32)
+    }
+}
+
+// scaling by whole numbers, like a facing direction or halving a size
+impl Mul<i32> for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: i32) -> Fixed {
+        Fixed(self.0 * other)
+    }
+}
+
+impl Div<i32> for Fixed {
+    type Output = Fixed;
+    fn div(self, other: i32) -> Fixed {
+        Fixed(self.0 / other)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, other: Fixed) {
+        *self = *self * other;
+    }
+}
+
+// The move data is written with decimals (`damage: 4.5`). RON parses
+// them into f64 the same way everywhere, and they are rounded to the
+// nearest thousandth once while loading
+impl<'de> Deserialize<'de> for Fixed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = f64::deserialize(deserializer)?;
+        Ok(Fixed((value * SCALE as f64).round() as i32))
+    }
+}
+
+// a position, speed or size in the simulation, see Fixed
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FixedVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FixedVec2 {
+    pub const ZERO: FixedVec2 = FixedVec2::new(Fixed::ZERO, Fixed::ZERO);
+    pub const X: FixedVec2 = FixedVec2::new(Fixed::ONE, Fixed::ZERO);
+
+    pub const fn new(x: Fixed, y: Fixed) -> Self {
+        Self { x, y }
+    }
+
+    pub fn abs(self) -> Self {
+        Self::new(self.x.abs(), self.y.abs())
+    }
+
+    // for drawing only, like Fixed::to_f32
+    pub fn to_vec2(self) -> Vec2 {
+        Vec2::new(self.x.to_f32(), self.y.to_f32())
+    }
+}
+
+impl Add for FixedVec2 {
+    type Output = FixedVec2;
+    fn add(self, other: FixedVec2) -> FixedVec2 {
+        FixedVec2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for FixedVec2 {
+    type Output = FixedVec2;
+    fn sub(self, other: FixedVec2) -> FixedVec2 {
+        FixedVec2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl Neg for FixedVec2 {
+    type Output = FixedVec2;
+    fn neg(self) -> FixedVec2 {
+        FixedVec2::new(-self.x, -self.y)
+    }
+}
+
+impl Mul<Fixed> for FixedVec2 {
+    type Output = FixedVec2;
+    fn mul(self, other: Fixed) -> FixedVec2 {
+        FixedVec2::new(self.x * other, self.y * other)
+    }
+}
+
+impl Div<i32> for FixedVec2 {
+    type Output = FixedVec2;
+    fn div(self, other: i32) -> FixedVec2 {
+        FixedVec2::new(self.x / other, self.y / other)
+    }
+}
+
+impl AddAssign for FixedVec2 {
+    fn add_assign(&mut self, other: FixedVec2) {
+        *self = *self + other;
+    }
+}
+
+impl<'de> Deserialize<'de> for FixedVec2 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (x, y) = <(Fixed, Fixed)>::deserialize(deserializer)?;
+        Ok(FixedVec2::new(x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn products_round_toward_zero() {
+        assert_eq!(Fixed::milli(1500) * Fixed::milli(1500), Fixed::milli(2250));
+        assert_eq!(Fixed::milli(1) * Fixed::milli(500), Fixed::ZERO);
+        assert_eq!(Fixed::milli(-1) * Fixed::milli(500), Fixed::ZERO);
+        assert_eq!(Fixed::milli(-333) * Fixed::from_int(3), Fixed::milli(-999));
+    }
+
+    #[test]
+    fn quotients_round_toward_zero() {
+        assert_eq!(Fixed::ONE / Fixed::from_int(3), Fixed::milli(333));
+        assert_eq!(-Fixed::ONE / Fixed::from_int(3), Fixed::milli(-333));
+        assert_eq!(Fixed::milli(-3) / 2, Fixed::milli(-1));
+    }
+
+    #[test]
+    fn products_dont_overflow_in_between() {
+        // the product of the raw thousandths only fits an i64 before scaling
+        assert_eq!(Fixed::from_int(3000) * Fixed::from_int(100), Fixed::from_int(300_000));
+    }
+
+    #[test]
+    fn friction_keeps_its_share() {
+        // what step_bodies does to a slide every frame
+        assert_eq!(Fixed::milli(40) * Fixed::milli(800), Fixed::milli(32));
+    }
+
+    #[test]
+    fn signum_and_abs() {
+        assert_eq!(Fixed::milli(-5).signum(), -1);
+        assert_eq!(Fixed::ZERO.signum(), 0);
+        assert_eq!(Fixed::milli(-5).abs(), Fixed::milli(5));
+    }
+
+    #[test]
+    fn move_data_rounds_to_the_nearest_thousandth() {
+        let parse = |text| ron::from_str::<Fixed>(text).unwrap();
+        assert_eq!(parse("4.5"), Fixed::milli(4500));
+        assert_eq!(parse("3"), Fixed::from_int(3));
+        assert_eq!(parse("0.1"), Fixed::milli(100));
+        assert_eq!(parse("0.0006"), Fixed::milli(1));
+        assert_eq!(parse("-0.0004"), Fixed::ZERO);
+        assert_eq!(parse("-0.85"), Fixed::milli(-850));
+    }
+
+    #[test]
+    fn move_data_vectors_are_tuples() {
+        let offset = ron::from_str::<FixedVec2>("(0.7, -0.1)").unwrap();
+        assert_eq!(offset, FixedVec2::new(Fixed::milli(700), Fixed::milli(-100)));
+    }
+}
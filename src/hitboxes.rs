@@ -1,77 +1,555 @@
-use bevy::{math::Vec3Swizzles,prelude::*};
+use bevy::prelude::*;
 use crate::components::*;
-use bevy_ggrs::ggrs;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
 
-use super::{GameDirection, Materials};
+use crate::args::Args;
+use crate::fixed::*;
+use crate::input::PlayerInput;
+use crate::physics::{wall_limit, Body, Position, Pushbox};
+use crate::states::forward;
 
+// upward speed a launcher gives the defender, a bit less than a jump
+const LAUNCH_SPEED: Fixed = Fixed::milli(250);
+
+// sent by whatever starts an attack to throw out a hitbox for one player.
+// `offset` is relative to the owner with +x pointing the way they face,
+// and the box stays out for `active` frames
 pub struct HitboxSpawnEvent {
-    
+    pub owner: usize,
+    pub width: Fixed,
+    pub height: Fixed,
+    pub offset: FixedVec2,
+    pub damage: Fixed,
+    pub chip: Fixed,
+    pub hitstun: u32,
+    pub blockstun: u32,
+    pub pushback: Fixed,
+    pub level: HitLevel,
+    pub launches: bool,
+    pub active: u32,
+    pub activation: i32,
+}
+
+// same as HitboxSpawnEvent, but for grabs
+pub struct GrabboxSpawnEvent {
+    pub owner: usize,
+    pub width: Fixed,
+    pub height: Fixed,
+    pub offset: FixedVec2,
+    pub active: u32,
+}
+
+// turn the facing-relative offset of a box into a world offset
+fn facing_offset(offset: FixedVec2, player: &Player) -> FixedVec2 {
+    FixedVec2::new(offset.x * forward(player.facing_direction) as i32, offset.y)
+}
+
+// Whether the pushboxes, hitboxes and grabboxes are drawn. They are a
+// debugging aid, so they stay hidden unless the `boxes` launch option or
+// F2 turns them on. Only the sprites care, the simulation is the same
+#[derive(Resource)]
+pub struct ShowBoxes(pub bool);
+
+pub fn toggle_boxes(keys: Res<Input<KeyCode>>, mut show: ResMut<ShowBoxes>) {
+    if keys.just_pressed(KeyCode::F2) {
+        show.0 = !show.0;
+    }
+}
+
+type BoxSprites<'w, 's> = Query<'w, 's, &'static mut Visibility, Or<(With<Hitbox>, With<Grabbox>)>>;
+
+// hides or shows the hitbox and grabbox sprites, including the ones
+// spawned since the last frame
+pub fn show_boxes(show: Res<ShowBoxes>, mut query: BoxSprites) {
+    for mut visibility in query.iter_mut() {
+        visibility.is_visible = show.0;
+    }
 }
 
-pub fn make_hitbox(
+// a translucent sprite so we can see the boxes while testing, see ShowBoxes
+fn box_sprite(position: FixedVec2, width: Fixed, height: Fixed, color: Color) -> SpriteBundle {
+    SpriteBundle {
+        transform: Transform::from_translation(position.to_vec2().extend(150.)),
+        sprite: Sprite {
+            color,
+            custom_size: Some(Vec2::new(width.to_f32(), height.to_f32())),
+            ..default()
+        },
+        ..default()
+    }
+}
+
+// spawns the requested hitboxes next to their owners. They are rollback
+// entities, so a box thrown out during a mispredicted frame disappears
+// again when GGRS rolls back
+pub fn spawn_hitboxes(
     mut commands: Commands,
-    mut player_query: Query<(&Transform, &Player)>,
-    mut materials: Materials,
-    newWidth: f32,
-    newHeight: f32,
-    xOffset: f32,
-    yOffset: f32,
-    newDamage: f32,
-    newChip: f32,
-    newHitstun: i32,
-    newBlockstun: i32
+    mut events: EventReader<HitboxSpawnEvent>,
+    player_query: Query<(&Position, &Player)>,
+    mut rip: ResMut<RollbackIdProvider>,
+    mut spawns: ResMut<SpawnCounter>,
 ) {
-    for (transform, player) in player_query.iter_mut() {
-        let player_pos = transform.translation.xy();
-        commands.spawn((
-            Hitbox {
-                width: newWidth,
-                height: newHeight,
-                position: player_pos + Vec2::new(xOffset, yOffset),
-                damage: newDamage,
-                chip: newChip,
-                hitstun: newHitstun,
-                blockstun: newBlockstun,
-                visibility: Visibility {
-                    is_visible: true,
+    for event in events.iter() {
+        for (player_position, player) in player_query.iter() {
+            if player.handle != event.owner {
+                continue;
+            }
+            let offset = facing_offset(event.offset, player);
+            let position = player_position.0 + offset;
+            commands.spawn((
+                Hitbox {
+                    width: event.width,
+                    height: event.height,
+                    position,
+                    damage: event.damage,
+                    chip: event.chip,
+                    hitstun: event.hitstun,
+                    blockstun: event.blockstun,
+                    pushback: event.pushback,
+                    level: event.level,
+                    launches: event.launches,
+                    visibility: Visibility {
+                        is_visible: true,
+                    },
+                    owner: event.owner,
+                    offset,
+                    active: event.active,
+                    activation: event.activation,
+                    connected: false,
                 },
-                ..default()
-            },
-            /*Sprite {
-                color: materials.hitbox_material.clone(),
-                custom_size: Some(Vec2::new(newWidth, newHeight)),
-                ..Default::default()
-            },*/
-        ));
+                Rollback::new(rip.next_id()),
+                spawns.next(),
+                box_sprite(position, event.width, event.height, Color::rgba(1., 0., 0., 0.5)),
+            ));
+        }
     }
 }
 
-pub fn make_grabbox(
+pub fn spawn_grabboxes(
     mut commands: Commands,
-    mut player_query: Query<(&Transform, &Player)>,
-    mut materials: Materials,
-    newWidth: f32,
-    newHeight: f32,
-    xOffset: f32,
-    yOffset: f32
+    mut events: EventReader<GrabboxSpawnEvent>,
+    player_query: Query<(&Position, &Player)>,
+    mut rip: ResMut<RollbackIdProvider>,
+    mut spawns: ResMut<SpawnCounter>,
 ) {
-    for (transform, player) in player_query.iter_mut() {
-        let player_pos = transform.translation.xy();
-        commands.spawn((
-            Grabbox {
-                width: newWidth,
-                height: newHeight,
-                position: player_pos + Vec2::new(xOffset, yOffset),
-                visibility: Visibility {
-                    is_visible: true,
+    for event in events.iter() {
+        for (player_position, player) in player_query.iter() {
+            if player.handle != event.owner {
+                continue;
+            }
+            let offset = facing_offset(event.offset, player);
+            let position = player_position.0 + offset;
+            commands.spawn((
+                Grabbox {
+                    width: event.width,
+                    height: event.height,
+                    position,
+                    visibility: Visibility {
+                        is_visible: true,
+                    },
+                    owner: event.owner,
+                    offset,
+                    active: event.active,
                 },
-                ..default()
-            },
-            /*sprite = SpriteBundle {
-                material: materials.hitbox_material.clone(),
-                sprite: Sprite::new(Vec2::new(newWidth, newHeight)),
-                ..Default::default()
-            };*/
-        ));
-    }
-}
\ No newline at end of file
+                Rollback::new(rip.next_id()),
+                spawns.next(),
+                box_sprite(position, event.width, event.height, Color::rgba(0., 0., 1., 0.5)),
+            ));
+        }
+    }
+}
+
+// keeps every hitbox attached to its owner as the owner moves
+pub fn attach_hitboxes(
+    player_query: Query<(&Position, &Player)>,
+    mut hitbox_query: Query<(&mut Hitbox, &mut Transform)>,
+) {
+    for (player_position, player) in player_query.iter() {
+        for (mut hitbox, mut transform) in hitbox_query.iter_mut() {
+            if hitbox.owner == player.handle {
+                hitbox.position = player_position.0 + hitbox.offset;
+                transform.translation = hitbox.position.to_vec2().extend(transform.translation.z);
+            }
+        }
+    }
+}
+
+pub fn attach_grabboxes(
+    player_query: Query<(&Position, &Player)>,
+    mut grabbox_query: Query<(&mut Grabbox, &mut Transform)>,
+) {
+    for (player_position, player) in player_query.iter() {
+        for (mut grabbox, mut transform) in grabbox_query.iter_mut() {
+            if grabbox.owner == player.handle {
+                grabbox.position = player_position.0 + grabbox.offset;
+                transform.translation = grabbox.position.to_vec2().extend(transform.translation.z);
+            }
+        }
+    }
+}
+
+// counts down the active frames of every box and removes the ones that
+// have run out
+pub fn expire_hitboxes(
+    mut commands: Commands,
+    mut hitbox_query: Query<(Entity, &mut Hitbox)>,
+    mut grabbox_query: Query<(Entity, &mut Grabbox)>,
+) {
+    for (entity, mut hitbox) in hitbox_query.iter_mut() {
+        hitbox.active = hitbox.active.saturating_sub(1);
+        if hitbox.active == 0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    for (entity, mut grabbox) in grabbox_query.iter_mut() {
+        grabbox.active = grabbox.active.saturating_sub(1);
+        if grabbox.active == 0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+// two rectangles given by their centers and sizes overlap if they are
+// closer than half their combined size on both axes
+fn overlaps(a_pos: FixedVec2, a_size: FixedVec2, b_pos: FixedVec2, b_size: FixedVec2) -> bool {
+    let distance = (a_pos - b_pos).abs();
+    let reach = (a_size + b_size) / 2;
+    distance.x < reach.x && distance.y < reach.y
+}
+
+// whether a guard in the given stance stops an attack of this level
+fn guards(level: HitLevel, crouching: bool) -> bool {
+    match level {
+        HitLevel::High | HitLevel::Mid => true,
+        HitLevel::Low => crouching,
+        HitLevel::Overhead => !crouching,
+    }
+}
+
+// The stance a defender guards an attack of this level in, `Some(true)`
+// for crouching. A defender holding back (down-back to guard crouching)
+// while free to act or already blocking guards the attack if the stance
+// matches its level. Fighters in the air can only guard when air
+// blocking is turned on
+fn guard_stance(fighter: &FighterState, input: PlayerInput, forward: i8, level: HitLevel, air_block: bool) -> Option<bool> {
+    let holding_back = input.holds_back(forward);
+    let crouching = input.down() && !fighter.is_airborne();
+    let free = if fighter.is_airborne() {
+        air_block && fighter.state == PlayerState::Jump
+    } else {
+        fighter.is_neutral()
+    };
+    let can_block = free || matches!(fighter.state, PlayerState::Block | PlayerState::BlockC);
+
+    (holding_back && can_block && guards(level, crouching)).then_some(crouching)
+}
+
+// A guarded attack only deals chip damage and puts the defender into
+// blockstun. Otherwise they take the full damage and go into hitstun, or
+// up into the air for a launcher
+fn take_hit(hitbox: &Hitbox, guard: Option<bool>, player: &mut Player, fighter: &mut FighterState, body: &mut Body) {
+    if let Some(crouching) = guard {
+        player.hp = (player.hp - hitbox.chip).max(Fixed::ZERO);
+        fighter.block(hitbox.blockstun, crouching);
+    } else if hitbox.launches {
+        player.hp = (player.hp - hitbox.damage).max(Fixed::ZERO);
+        fighter.launch();
+        body.velocity.y = LAUNCH_SPEED;
+        body.grounded = false;
+    } else {
+        player.hp = (player.hp - hitbox.damage).max(Fixed::ZERO);
+        fighter.hit(hitbox.hitstun);
+    }
+}
+
+// Tests every active hitbox against the hurtboxes of the other players,
+// see guard_stance and take_hit for what happens when one connects
+pub fn resolve_hits(
+    args: Res<Args>,
+    mut hitbox_query: Query<(&SpawnId, &mut Hitbox)>,
+    mut player_query: Query<(&Position, &Hurtbox, &Pushbox, &Controls, &mut Body, &mut Player, &mut FighterState)>,
+) {
+    // attackers that hit a cornered defender and get pushed back instead
+    let mut recoils = Vec::new();
+    // where the attackers stand and which way they face, to push their
+    // defenders the right way
+    let owners: Vec<(usize, Fixed, i32)> = player_query
+        .iter()
+        .map(|(position, .., player, _)| (player.handle, position.0.x, forward(player.facing_direction) as i32))
+        .collect();
+    // the move activations that already connected, by owner
+    let mut connected: Vec<(usize, i32)> = player_query
+        .iter()
+        .filter_map(|(.., player, fighter)| Some((player.handle, fighter.last_connected?)))
+        .collect();
+
+    // go through the hitboxes in spawn order so that the outcome of
+    // several boxes connecting on the same frame doesn't depend on the
+    // order the entities happen to be stored in
+    let mut hitboxes: Vec<_> = hitbox_query.iter_mut().collect();
+    hitboxes.sort_by_key(|(id, _)| **id);
+
+    for (_, mut hitbox) in hitboxes {
+        if hitbox.connected || connected.contains(&(hitbox.owner, hitbox.activation)) {
+            continue;
+        }
+        let hitbox_size = FixedVec2::new(hitbox.width, hitbox.height);
+
+        for (position, hurtbox, pushbox, controls, mut body, mut player, mut fighter) in player_query.iter_mut() {
+            // fighters in a throw can't be hit until it's over
+            if player.handle == hitbox.owner || fighter.state == PlayerState::Defeat || fighter.in_throw() {
+                continue;
+            }
+            if hitbox.level == HitLevel::High && fighter.is_crouching() {
+                continue; // ducked under it
+            }
+            let hurtbox_pos = position.0 + hurtbox.offset;
+            let hurtbox_size = FixedVec2::new(hurtbox.width, hurtbox.height);
+            if !overlaps(hitbox.position, hitbox_size, hurtbox_pos, hurtbox_size) {
+                continue;
+            }
+
+            let forward = forward(player.facing_direction);
+            let guard = guard_stance(&fighter, controls.current(), forward, hitbox.level, args.air_block);
+            take_hit(&hitbox, guard, &mut player, &mut fighter, &mut body);
+            // push the defender away from the attacker. Whatever doesn't
+            // fit between a cornered defender and the wall pushes the
+            // attacker back instead
+            let direction = match owners.iter().find(|(handle, ..)| *handle == hitbox.owner) {
+                // right on top of each other, push the way the attacker faces
+                Some(&(_, owner_x, owner_forward)) if owner_x == position.0.x => owner_forward,
+                Some(&(_, owner_x, _)) => (position.0.x - owner_x).signum(),
+                None => hitbox.offset.x.signum(),
+            };
+            let room = (wall_limit(Some(pushbox)) - position.0.x * direction).max(Fixed::ZERO);
+            let push = hitbox.pushback.min(room);
+            body.slide(push * direction);
+            if push < hitbox.pushback {
+                recoils.push((hitbox.owner, (hitbox.pushback - push) * -direction));
+            }
+            hitbox.connected = true;
+            connected.push((hitbox.owner, hitbox.activation));
+            break;
+        }
+    }
+
+    // attackers remember the move that connected, so its later boxes pass
+    // through. One that pushed several defenders into a corner this frame
+    // goes back by all of it
+    for (.., mut body, player, mut fighter) in player_query.iter_mut() {
+        if let Some(&(_, activation)) = connected.iter().rev().find(|(owner, _)| *owner == player.handle) {
+            fighter.last_connected = Some(activation);
+        }
+        let recoil = recoils
+            .iter()
+            .filter(|(owner, _)| *owner == player.handle)
+            .fold(Fixed::ZERO, |total, &(_, distance)| total + distance);
+        if recoil != Fixed::ZERO {
+            body.slide(recoil);
+        }
+    }
+}
+
+// Tests every active grabbox against the hurtboxes of the other players.
+// A grab only catches an opponent that is throwable (see
+// FighterState::is_throwable), and goes straight through a guard. The
+// grabber throws forward, or backward while holding back, and both
+// fighters are locked into the throw until resolve_throws is done with it
+pub fn resolve_grabs(
+    mut grabbox_query: Query<(&SpawnId, &mut Grabbox)>,
+    mut player_query: Query<(Entity, &Position, &Hurtbox, &Controls, &Player, &mut FighterState)>,
+) {
+    let mut grabboxes: Vec<_> = grabbox_query.iter_mut().collect();
+    grabboxes.sort_by_key(|(id, _)| **id);
+
+    for (_, mut grabbox) in grabboxes {
+        let grabbox_size = FixedVec2::new(grabbox.width, grabbox.height);
+
+        // a grabber that got hit before the box connected lost the grab
+        let grabber = player_query
+            .iter()
+            .find(|(.., player, fighter)| player.handle == grabbox.owner && fighter.state == PlayerState::Grab)
+            .map(|(entity, ..)| entity);
+        let Some(attacker_entity) = grabber else {
+            continue;
+        };
+
+        let mut targets: Vec<_> = player_query
+            .iter()
+            .filter(|(_, position, hurtbox, _, player, fighter)| {
+                let hurtbox_pos = position.0 + hurtbox.offset;
+                let hurtbox_size = FixedVec2::new(hurtbox.width, hurtbox.height);
+                player.handle != grabbox.owner
+                    && fighter.is_throwable()
+                    && overlaps(grabbox.position, grabbox_size, hurtbox_pos, hurtbox_size)
+            })
+            .map(|(entity, .., player, _)| (player.handle, entity))
+            .collect();
+        targets.sort_by_key(|(handle, _)| *handle);
+        let Some(&(defender_handle, defender_entity)) = targets.first() else {
+            continue;
+        };
+
+        let [attacker, defender] = player_query.get_many_mut([attacker_entity, defender_entity]).unwrap();
+        let (.., controls, player, mut fighter) = attacker;
+        let backward = controls.current().holds_back(forward(player.facing_direction));
+        fighter.throw(!backward, defender_handle);
+        let (.., mut fighter) = defender;
+        fighter.thrown(grabbox.owner);
+
+        // one throw per grab: let expire_hitboxes remove the box this frame
+        grabbox.active = 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Button;
+
+    use HitLevel::*;
+    use PlayerState::*;
+
+    fn holding(buttons: &[Button]) -> PlayerInput {
+        let mut input = PlayerInput::default();
+        for &button in buttons {
+            input.set(button);
+        }
+        input
+    }
+
+    fn fighter(state: PlayerState) -> FighterState {
+        let mut fighter = FighterState::default();
+        fighter.enter(state);
+        fighter
+    }
+
+    fn airborne(state: PlayerState) -> FighterState {
+        let mut fighter = fighter(state);
+        fighter.air_frames = 5;
+        fighter
+    }
+
+    // facing right, so back is left
+    fn guard(fighter: &FighterState, buttons: &[Button], level: HitLevel, air_block: bool) -> Option<bool> {
+        guard_stance(fighter, holding(buttons), 1, level, air_block)
+    }
+
+    const BACK: &[Button] = &[Button::Left];
+    const DOWN_BACK: &[Button] = &[Button::Left, Button::Down];
+
+    #[test]
+    fn levels_against_stances() {
+        assert!(guards(High, false) && guards(High, true));
+        assert!(guards(Mid, false) && guards(Mid, true));
+        assert!(!guards(Low, false) && guards(Low, true));
+        assert!(guards(Overhead, false) && !guards(Overhead, true));
+    }
+
+    #[test]
+    fn holding_back_guards_in_the_held_stance() {
+        assert_eq!(guard(&fighter(WalkB), BACK, Mid, false), Some(false));
+        assert_eq!(guard(&fighter(Crouch), DOWN_BACK, Mid, false), Some(true));
+        assert_eq!(guard(&fighter(Crouch), DOWN_BACK, Low, false), Some(true));
+        assert_eq!(guard(&fighter(Idle), BACK, Overhead, false), Some(false));
+    }
+
+    #[test]
+    fn wrong_stance_gets_hit() {
+        assert_eq!(guard(&fighter(WalkB), BACK, Low, false), None);
+        assert_eq!(guard(&fighter(Crouch), DOWN_BACK, Overhead, false), None);
+    }
+
+    #[test]
+    fn back_is_mirrored_when_facing_left() {
+        let fighter = fighter(Idle);
+        assert_eq!(guard_stance(&fighter, holding(&[Button::Right]), -1, Mid, false), Some(false));
+        assert_eq!(guard_stance(&fighter, holding(&[Button::Left]), -1, Mid, false), None);
+    }
+
+    #[test]
+    fn not_holding_back_gets_hit() {
+        assert_eq!(guard(&fighter(Idle), &[], Mid, false), None);
+        assert_eq!(guard(&fighter(Walk), &[Button::Right], Mid, false), None);
+        assert_eq!(guard(&fighter(Crouch), &[Button::Down], Low, false), None);
+    }
+
+    #[test]
+    fn only_free_or_blocking_fighters_guard() {
+        assert_eq!(guard(&fighter(PunchL), BACK, Mid, false), None);
+        assert_eq!(guard(&fighter(Hitstun), BACK, Mid, false), None);
+        assert_eq!(guard(&fighter(Dash), BACK, Mid, false), None);
+        // blocking fighters keep blocking, and can switch stance
+        assert_eq!(guard(&fighter(Block), BACK, Mid, false), Some(false));
+        assert_eq!(guard(&fighter(Block), DOWN_BACK, Low, false), Some(true));
+        assert_eq!(guard(&fighter(BlockC), BACK, Overhead, false), Some(false));
+    }
+
+    #[test]
+    fn air_blocking_has_to_be_turned_on() {
+        assert_eq!(guard(&airborne(Jump), BACK, Mid, false), None);
+        assert_eq!(guard(&airborne(Jump), BACK, Mid, true), Some(false));
+        // down doesn't crouch in the air, so lows still hit
+        assert_eq!(guard(&airborne(Jump), DOWN_BACK, Low, true), None);
+        assert_eq!(guard(&airborne(PunchLA), BACK, Mid, true), None);
+        assert_eq!(guard(&airborne(Block), BACK, Mid, false), Some(false));
+    }
+
+    fn hitbox(launches: bool) -> Hitbox {
+        Hitbox {
+            damage: Fixed::from_int(10),
+            chip: Fixed::ONE,
+            hitstun: 20,
+            blockstun: 14,
+            launches,
+            ..default()
+        }
+    }
+
+    fn take(hitbox: &Hitbox, guard: Option<bool>, state: PlayerState) -> (Player, FighterState, Body) {
+        let mut player = Player { hp: Fixed::from_int(100), ..default() };
+        let mut fighter = fighter(state);
+        let mut body = Body::grounded(Fixed::milli(800));
+        take_hit(hitbox, guard, &mut player, &mut fighter, &mut body);
+        (player, fighter, body)
+    }
+
+    #[test]
+    fn guarded_attacks_only_chip() {
+        let (player, fighter, _) = take(&hitbox(false), Some(true), Crouch);
+        assert_eq!(player.hp, Fixed::from_int(99));
+        assert_eq!((fighter.state, fighter.stun, fighter.frame), (BlockC, 14, 0));
+    }
+
+    #[test]
+    fn hits_deal_full_damage_and_hitstun() {
+        let (player, fighter, _) = take(&hitbox(false), None, Crouch);
+        assert_eq!(player.hp, Fixed::from_int(90));
+        assert_eq!((fighter.state, fighter.stun), (HitstunC, 20));
+        let (_, fighter, _) = take(&hitbox(false), None, Idle);
+        assert_eq!(fighter.state, Hitstun);
+    }
+
+    #[test]
+    fn launchers_launch_on_hit_only() {
+        let (player, fighter, body) = take(&hitbox(true), None, Idle);
+        assert_eq!(player.hp, Fixed::from_int(90));
+        assert_eq!(fighter.state, Launch);
+        assert!(fighter.is_airborne() && !body.grounded);
+        assert_eq!(body.velocity.y, LAUNCH_SPEED);
+
+        let (_, fighter, body) = take(&hitbox(true), Some(false), Idle);
+        assert_eq!(fighter.state, Block);
+        assert!(body.grounded);
+    }
+
+    #[test]
+    fn hp_does_not_go_below_zero() {
+        let mut player = Player { hp: Fixed::milli(500), ..default() };
+        let mut fighter = fighter(Idle);
+        let mut body = Body::grounded(Fixed::milli(800));
+        take_hit(&hitbox(false), None, &mut player, &mut fighter, &mut body);
+        assert_eq!(player.hp, Fixed::ZERO);
+    }
+}
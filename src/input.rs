@@ -1,124 +1,444 @@
 use bevy::prelude::*;
-use bevy_ggrs::ggrs;
-
-// Our input needs to be encoded to the u8 we defined in the GgrsConfig
-// type and handed over to GGRS.
-// Define some bit mask constants to signify what bit means what:
-const INPUT_UP: u8 = 1 << 0;
-const INPUT_DOWN: u8 = 1 << 1;
-const INPUT_LEFT: u8 = 1 << 2;
-const INPUT_RIGHT: u8 = 1 << 3;
-const INPUT_LP: u8 = 1 << 4;
-const INPUT_HP: u8 = 1 << 5;
-const INPUT_LK: u8 = 1 << 6;
-const INPUT_HK: u8 = 1 << 7;
+use bevy_ggrs::{ggrs, PlayerInputs};
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
 
-// move the input sampling from move_player into a special input 
-// system. This system need to return the same type we defined in our 
-// GgrsConfig type, a u8.
-pub fn input(_: In<ggrs::PlayerHandle>, keys: Res<Input<KeyCode>>) -> u8 {
-    let mut input = 0u8;
+use crate::args::Args;
+use crate::bindings::{Bindings, KeySet};
+use crate::components::{Controls, Player, INPUT_HISTORY};
+use crate::{GameState, GgrsConfig};
+
+// Every button a player can hold. The variant's position is its bit in
+// `PlayerInput`, so a new button only needs a variant here and default
+// keys in bindings.rs. There is room for 16 of them.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    LightPunch,
+    HeavyPunch,
+    LightKick,
+    HeavyKick,
+    Block,
+    Grab,
+    Special,
+    Start,
+    Taunt,
+}
 
-    if keys.any_pressed([KeyCode::Up, KeyCode::W]) {
-        input |= INPUT_UP;
+impl Button {
+    fn bit(self) -> u16 {
+        1 << self as u16
     }
-    if keys.any_pressed([KeyCode::Down, KeyCode::S]) {
-        input |= INPUT_DOWN;
+}
+
+// One frame of a player's input, the type GGRS sends to the other peers.
+// It is plain bytes (`Pod`), so it gets copied over the network as is:
+// two bytes per frame, in the platform's byte order (little endian on
+// everything we run on, wasm included)
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug, Reflect, Pod, Zeroable)]
+pub struct PlayerInput {
+    bits: u16,
+}
+
+impl PlayerInput {
+    pub fn held(self, button: Button) -> bool {
+        self.bits & button.bit() != 0
     }
-    if keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-        input |= INPUT_LEFT;
+
+    pub fn set(&mut self, button: Button) {
+        self.bits |= button.bit();
     }
-    if keys.any_pressed([KeyCode::Right, KeyCode::D]) {
-        input |= INPUT_RIGHT;
+
+    pub fn clear(&mut self, button: Button) {
+        self.bits &= !button.bit();
     }
-    if keys.just_pressed(KeyCode::O) {
-        input |= INPUT_LP;
+
+    // the buttons held now that weren't held in `previous`
+    pub fn newly_held(self, previous: PlayerInput) -> PlayerInput {
+        PlayerInput { bits: self.bits & !previous.bits }
     }
-    if keys.just_pressed(KeyCode::P) {
-        input |= INPUT_HP;
+
+    pub fn up(self) -> bool {
+        self.held(Button::Up)
     }
-    if keys.just_pressed(KeyCode::L) {
-        input |= INPUT_LK;
+
+    pub fn down(self) -> bool {
+        self.held(Button::Down)
     }
-    if keys.just_pressed(KeyCode::Semicolon) {
-        input |= INPUT_HK;
+
+    pub fn lightpunch(self) -> bool {
+        self.held(Button::LightPunch)
     }
 
-    input
+    pub fn heavypunch(self) -> bool {
+        self.held(Button::HeavyPunch)
+    }
+
+    pub fn lightkick(self) -> bool {
+        self.held(Button::LightKick)
+    }
+
+    pub fn heavykick(self) -> bool {
+        self.held(Button::HeavyKick)
+    }
+
+    // +1 while holding toward the way the fighter faces (`forward` as
+    // returned by states::forward), -1 while holding away, 0 otherwise.
+    // Up and down don't move a fighter directly: crouching is a state,
+    // and jumps follow their own arcs (see jump.rs)
+    pub fn horizontal(self, forward: i8) -> i8 {
+        (self.held(Button::Right) as i8 - self.held(Button::Left) as i8) * forward
+    }
+
+    pub fn holds_forward(self, forward: i8) -> bool {
+        self.horizontal(forward) > 0
+    }
+
+    pub fn holds_back(self, forward: i8) -> bool {
+        self.horizontal(forward) < 0
+    }
+
+    // the grab button, or light punch and light kick together
+    pub fn grab(self) -> bool {
+        self.held(Button::Grab) || (self.lightpunch() && self.lightkick())
+    }
+
+    // the bullets still come out of the light punch button
+    pub fn fire(self) -> bool {
+        self.lightpunch()
+    }
+
+    pub fn any_attack(self) -> bool {
+        self.lightpunch() || self.heavypunch() || self.lightkick() || self.heavykick()
+    }
+}
+
+// What to do when opposite directions are held at the same time
+// (simultaneous opposing cardinal directions, SOCD). Keyboards and
+// all-button controllers can do that, sticks can't, so it's resolved
+// before the input is sent and both peers see the same cleaned input.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HorizontalSocd {
+    // left + right is neither
+    Neutral,
+    // left + right is whichever of the two went down last
+    LastInputWins,
 }
 
-// makes player move on keyboard / samples the keyboard and moves 
-// any objects with the Player marker component in the given direction
-// / convert the low-level input format to a direction
-pub fn direction(input: u8) -> Vec2 {
-    let mut direction = Vec2::ZERO;
-    if input & INPUT_UP != 0 {
-        //direction.y += 1.;
-        
-        // STEP 09 jumping code:
-        // if !jumper.is_jumping {
-            //velocity.linvel = Vec2::new(0., jumper.jump_impulse).into();
-            //jumper.is_jumping = true
-        //}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerticalSocd {
+    // up + down is up
+    UpPriority,
+    // up + down is neither
+    Neutral,
+}
+
+impl std::str::FromStr for HorizontalSocd {
+    type Err = ();
 
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "neutral" => Ok(HorizontalSocd::Neutral),
+            "last" => Ok(HorizontalSocd::LastInputWins),
+            _ => Err(()),
+        }
     }
+}
 
-    if input & INPUT_DOWN != 0 {
-        //direction.y -= 1.;
+impl std::str::FromStr for VerticalSocd {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "up" => Ok(VerticalSocd::UpPriority),
+            "neutral" => Ok(VerticalSocd::Neutral),
+            _ => Err(()),
+        }
     }
+}
 
-    if input & INPUT_RIGHT != 0 {
-        direction.x += 1.;
-        // STEP 09 jumping code:
-        //if keyboard_input.pressed(KeyCode::D) && !jumper.is_jumping {
-            //velocity.linvel = Vec2::new(player.speed, velocity.linvel.y).into();
-            //player.facing_direction = GameDirection::Right
-            //if keyboard_input.just_pressed(KeyCode::W) {
-                //velocity.linvel = Vec2::new(player.speed, jumper.jump_impulse).into();
-                //jumper.is_jumping = true
-            //}
-        //}
-        
+// what the SOCD cleaning remembers about one local player between frames
+#[derive(Default, Clone, Copy)]
+pub struct SocdState {
+    previous: PlayerInput,
+    last_horizontal: Option<Button>,
+}
+
+impl SocdState {
+    // resolve opposing directions in a freshly sampled input
+    fn clean(&mut self, input: &mut PlayerInput, horizontal: HorizontalSocd, vertical: VerticalSocd) {
+        let pressed = input.newly_held(self.previous);
+        self.previous = *input;
+        for button in [Button::Left, Button::Right] {
+            if pressed.held(button) {
+                self.last_horizontal = Some(button);
+            }
+        }
+
+        if input.held(Button::Left) && input.held(Button::Right) {
+            input.clear(Button::Left);
+            input.clear(Button::Right);
+            if let (HorizontalSocd::LastInputWins, Some(button)) = (horizontal, self.last_horizontal) {
+                input.set(button);
+            }
+        }
+        if input.up() && input.down() {
+            input.clear(Button::Down);
+            if vertical == VerticalSocd::Neutral {
+                input.clear(Button::Up);
+            }
+        }
     }
+}
 
-    if input & INPUT_LEFT != 0 {
-        direction.x -= 1.;
-        // STEP 09 jumping code:
-        //if keyboard_input.pressed(KeyCode::A) && !jumper.is_jumping {
-            //velocity.linvel = Vec2::new(-player.speed, velocity.linvel.y).into();
-            //if keyboard_input.just_pressed(KeyCode::W) {
-                //velocity.linvel = Vec2::new(-player.speed, jumper.jump_impulse).into();
-                //jumper.is_jumping = true
-            //}
-        //}
+// where a player's input comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Device {
+    Keyboard,
+    // the gamepad with this id. Pads are numbered in the order they were
+    // connected, starting at 0
+    Gamepad(usize),
+}
 
-        // momentum control (prevents sliding)
-        // if (keyboard_input.just_released(KeyCode::A) || keyboard_input.just_released(KeyCode::D)) && !jumper.is_jumping {
-            // velocity.linvel = Vec2::new(0., velocity.linvel.y).into();
-        // }
+// `keyboard`, `gamepad` (the first one) or `gamepad1`, `gamepad2` ...
+impl std::str::FromStr for Device {
+    type Err = ();
 
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "keyboard" => Ok(Device::Keyboard),
+            "gamepad" => Ok(Device::Gamepad(0)),
+            _ => value
+                .strip_prefix("gamepad")
+                .and_then(|id| id.parse().ok())
+                .map(Device::Gamepad)
+                .ok_or(()),
+        }
     }
+}
 
-    direction.normalize_or_zero()
+fn keyboard_input(keys: &Input<KeyCode>, key_set: &KeySet) -> PlayerInput {
+    let mut input = PlayerInput::default();
+    for (&button, codes) in key_set {
+        if keys.any_pressed(codes.iter().copied()) {
+            input.set(button);
+        }
+    }
+    input
 }
 
-// check for the fire button
-pub fn fire(input: u8) -> bool {
-    input & INPUT_LP != 0
+// The pad's buttons (d-pad included) and its left stick. Outside the
+// deadzone the stick snaps to the nearest of 8 directions, so it can't
+// produce anything a d-pad or keyboard couldn't.
+fn gamepad_input(
+    gamepad: Gamepad,
+    buttons: &Input<GamepadButton>,
+    axes: &Axis<GamepadAxis>,
+    bindings: &Bindings,
+) -> PlayerInput {
+    let mut input = PlayerInput::default();
+    for (&button, codes) in &bindings.gamepad {
+        if codes.iter().any(|&code| buttons.pressed(GamepadButton::new(gamepad, code))) {
+            input.set(button);
+        }
+    }
+
+    let axis = |axis_type| axes.get(GamepadAxis::new(gamepad, axis_type)).unwrap_or(0.);
+    let stick = Vec2::new(axis(GamepadAxisType::LeftStickX), axis(GamepadAxisType::LeftStickY));
+    if stick.length() > bindings.stick_deadzone {
+        // 0 is right, counting counter-clockwise in steps of 45 degrees
+        let octant = (stick.y.atan2(stick.x) / std::f32::consts::FRAC_PI_4).round() as i32;
+        let directions: &[Button] = match octant.rem_euclid(8) {
+            0 => &[Button::Right],
+            1 => &[Button::Right, Button::Up],
+            2 => &[Button::Up],
+            3 => &[Button::Up, Button::Left],
+            4 => &[Button::Left],
+            5 => &[Button::Left, Button::Down],
+            6 => &[Button::Down],
+            _ => &[Button::Down, Button::Right],
+        };
+        for &direction in directions {
+            input.set(direction);
+        }
+    }
+    input
 }
 
-pub fn lightpunch(input: u8) -> bool {
-    input & INPUT_LP != 0
+// move the input sampling from move_player into a special input 
+// system. This system need to return the same type we defined in our 
+// GgrsConfig type, a PlayerInput.
+#[allow(clippy::too_many_arguments)]
+pub fn input(
+    handle: In<ggrs::PlayerHandle>,
+    keys: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    args: Res<Args>,
+    bindings: Res<Bindings>,
+    state: Res<State<GameState>>,
+    mut socd: Local<Vec<SocdState>>,
+) -> PlayerInput {
+    // keys pressed while rebinding are meant for the rebinding screen
+    if *state.current() == GameState::Rebinding {
+        return PlayerInput::default();
+    }
+
+    // only send what is held right now. Presses and releases are worked
+    // out from that in the simulation, see update_controls
+    let mut input = PlayerInput::default();
+    if args.plays_locally() {
+        // every player on their own device (or their half of the keyboard),
+        // in synctests as well so the two fighters don't mirror each other
+        input = match args.devices[handle.0] {
+            Device::Keyboard => keyboard_input(&keys, &bindings.local[handle.0]),
+            Device::Gamepad(id) => gamepad_input(Gamepad::new(id), &gamepad_buttons, &gamepad_axes, &bindings),
+        };
+    } else {
+        // online the only local player can use the keyboard and any pad
+        input.bits |= keyboard_input(&keys, &bindings.online).bits;
+        for gamepad in gamepads.iter() {
+            input.bits |= gamepad_input(gamepad, &gamepad_buttons, &gamepad_axes, &bindings).bits;
+        }
+    }
+
+    if socd.len() <= handle.0 {
+        socd.resize(handle.0 + 1, SocdState::default());
+    }
+    socd[handle.0].clean(&mut input, args.socd_x, args.socd_y);
+
+    input
 }
 
-pub fn heavypunch(input: u8) -> bool {
-    input & INPUT_HP != 0
+impl Controls {
+    // the input `frames_ago` frames before this one
+    pub fn held_at(&self, frames_ago: usize) -> PlayerInput {
+        self.held[(self.head + INPUT_HISTORY - frames_ago % INPUT_HISTORY) % INPUT_HISTORY]
+    }
+
+    // what is held this frame
+    pub fn current(&self) -> PlayerInput {
+        self.held_at(0)
+    }
+
+    // buttons that went down this frame
+    pub fn pressed(&self) -> PlayerInput {
+        self.current().newly_held(self.held_at(1))
+    }
+
+    // buttons that came up this frame
+    pub fn released(&self) -> PlayerInput {
+        self.held_at(1).newly_held(self.current())
+    }
+
+    // every press from the last `window` frames (this one included) that
+    // hasn't been consumed yet
+    pub fn buffered(&self, window: usize) -> PlayerInput {
+        let mut buffered = PlayerInput::default();
+        for frames_ago in 0..window.clamp(1, INPUT_HISTORY) {
+            let index = (self.head + INPUT_HISTORY - frames_ago) % INPUT_HISTORY;
+            buffered.bits |= self.presses[index].bits;
+        }
+        buffered
+    }
+
+    // forget the buffered presses of these buttons, so the move they
+    // started doesn't come out a second time
+    pub fn consume(&mut self, buttons: PlayerInput) {
+        for presses in self.presses.iter_mut() {
+            presses.bits &= !buttons.bits;
+        }
+    }
+
+    fn push(&mut self, input: PlayerInput) {
+        let previous = self.current();
+        self.head = (self.head + 1) % INPUT_HISTORY;
+        self.held[self.head] = input;
+        self.presses[self.head] = input.newly_held(previous);
+    }
 }
 
-pub fn lightkick(input: u8) -> bool {
-    input & INPUT_LK != 0
+// the first thing every simulation frame does: add this frame's input
+// from GGRS to the buffer. Controls is rolled back, so a
+// resimulated frame sees exactly the same presses as the first time
+pub fn update_controls(
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut query: Query<(&Player, &mut Controls)>,
+) {
+    for (player, mut controls) in query.iter_mut() {
+        controls.push(inputs[player.handle].0);
+    }
 }
 
-pub fn heavykick(input: u8) -> bool {
-    input & INPUT_HK != 0
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(buttons: &[Button]) -> PlayerInput {
+        let mut input = PlayerInput::default();
+        for &button in buttons {
+            input.set(button);
+        }
+        input
+    }
+
+    // cleans one frame of held buttons and returns what gets sent
+    fn clean(socd: &mut SocdState, buttons: &[Button], horizontal: HorizontalSocd, vertical: VerticalSocd) -> PlayerInput {
+        let mut input = holding(buttons);
+        socd.clean(&mut input, horizontal, vertical);
+        input
+    }
+
+    #[test]
+    fn left_and_right_cancel_out() {
+        let mut socd = SocdState::default();
+        let both = [Button::Left, Button::Right];
+        let input = clean(&mut socd, &both, HorizontalSocd::Neutral, VerticalSocd::UpPriority);
+        assert_eq!(input, PlayerInput::default());
+    }
+
+    #[test]
+    fn last_horizontal_input_wins() {
+        let mut socd = SocdState::default();
+        let (horizontal, vertical) = (HorizontalSocd::LastInputWins, VerticalSocd::UpPriority);
+        clean(&mut socd, &[Button::Left], horizontal, vertical);
+        let input = clean(&mut socd, &[Button::Left, Button::Right], horizontal, vertical);
+        assert_eq!(input, holding(&[Button::Right]));
+        // still holding both, right stays the newer one
+        let input = clean(&mut socd, &[Button::Left, Button::Right], horizontal, vertical);
+        assert_eq!(input, holding(&[Button::Right]));
+        // letting go of right goes back to left
+        let input = clean(&mut socd, &[Button::Left], horizontal, vertical);
+        assert_eq!(input, holding(&[Button::Left]));
+    }
+
+    #[test]
+    fn right_is_newer_when_both_go_down_together() {
+        let mut socd = SocdState::default();
+        let both = [Button::Left, Button::Right];
+        let input = clean(&mut socd, &both, HorizontalSocd::LastInputWins, VerticalSocd::UpPriority);
+        // both went down together, the one checked last counts as newer
+        assert_eq!(input, holding(&[Button::Right]));
+    }
+
+    #[test]
+    fn up_wins_over_down() {
+        let mut socd = SocdState::default();
+        let both = [Button::Up, Button::Down, Button::LightPunch];
+        let input = clean(&mut socd, &both, HorizontalSocd::Neutral, VerticalSocd::UpPriority);
+        assert_eq!(input, holding(&[Button::Up, Button::LightPunch]));
+    }
+
+    #[test]
+    fn up_and_down_can_cancel_out() {
+        let mut socd = SocdState::default();
+        let both = [Button::Up, Button::Down, Button::Right];
+        let input = clean(&mut socd, &both, HorizontalSocd::Neutral, VerticalSocd::Neutral);
+        assert_eq!(input, holding(&[Button::Right]));
+    }
+}
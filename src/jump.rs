@@ -0,0 +1,55 @@
+use bevy::prelude::*;
+
+use crate::components::*;
+use crate::fixed::*;
+use crate::physics::Body;
+use crate::states::forward;
+
+// sideways speed of forward and back jumps, fixed for the whole jump
+const JUMP_FORWARD_SPEED: Fixed = Fixed::milli(70);
+const JUMP_BACK_SPEED: Fixed = Fixed::milli(60);
+
+impl Jumper {
+    // leave the ground on one of the three arcs: `horizontal` is +1 for a
+    // forward jump, -1 for a back jump and 0 for a neutral one
+    fn take_off(&mut self, body: &mut Body, horizontal: i8, forward: i8) {
+        let speed = match horizontal {
+            1 => JUMP_FORWARD_SPEED,
+            -1 => -JUMP_BACK_SPEED,
+            _ => Fixed::ZERO,
+        };
+        body.velocity = FixedVec2::new(speed * forward as i32, self.jump_impulse);
+        body.grounded = false;
+        self.is_jumping = true;
+    }
+}
+
+// Starts the jumps. A fighter that just finished its prejump frames takes
+// off in the direction held right now, and keeps that trajectory no
+// matter what happens to the stick afterwards. Fighters knocked into the
+// air without jumping simply fall. The arc itself is up to step_bodies
+pub fn apply_jumps(mut query: Query<(&Player, &Controls, &mut Jumper, &mut Body, &FighterState)>) {
+    for (player, controls, mut jumper, mut body, fighter) in query.iter_mut() {
+        if jumper.is_jumping {
+            continue;
+        }
+        if fighter.state == PlayerState::Jump {
+            let forward = forward(player.facing_direction);
+            jumper.take_off(&mut body, controls.current().horizontal(forward), forward);
+        } else if fighter.is_airborne() {
+            body.grounded = false;
+            jumper.is_jumping = true;
+        }
+    }
+}
+
+// ends the jumps of the fighters step_bodies put back on the floor (see
+// FighterState::land)
+pub fn land_jumpers(mut query: Query<(&mut Jumper, &Body, &mut FighterState)>) {
+    for (mut jumper, body, mut fighter) in query.iter_mut() {
+        if jumper.is_jumping && body.grounded {
+            jumper.is_jumping = false;
+            fighter.land();
+        }
+    }
+}
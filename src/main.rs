@@ -1,15 +1,37 @@
-use bevy::{math::Vec3Swizzles, prelude::*, render::camera::ScalingMode, tasks::IoTaskPool};
+use args::{Args, MAX_PREDICTION};
+use bindings::*;
+use checksum::*;
+use connection::*;
+use bevy::{prelude::*, tasks::IoTaskPool};
 use bevy_asset_loader::prelude::*;
 use bevy_ggrs::{ggrs::PlayerType, *};
 use components::*;
+use fixed::*;
+use hitboxes::*;
 use input::*;
+use jump::*;
+use moves::*;
+use physics::*;
+use stage::*;
+use states::*;
 use matchbox_socket::WebRtcSocket;
-use bevy_rapier2d::prelude::*; // floor and gravity
 //use super::components::{Jumper, Materials, Player}; // inserting Jumper
 
+mod args;
+mod bindings;
+mod checksum;
 mod components;
+mod connection;
+mod fixed;
 mod input;
+mod jump;
+mod motions;
 mod hitboxes;
+mod moves;
+mod physics;
+mod stage;
+mod states;
+mod ui;
 
 // store the matchbox socket somewhere: it's accessible from multiple 
 // systems, so create a new resource for things related to the current
@@ -25,8 +47,8 @@ struct Session {
 struct GgrsConfig;
 
 impl ggrs::Config for GgrsConfig {
-    // 4-directions + fire fits easily in a single byte
-    type Input = u8;
+    // directions and buttons, packed into a bitfield
+    type Input = PlayerInput;
     type State = u8;
     // Matchbox' WebRtcSocket addresses are strings
     type Address = String;
@@ -40,15 +62,12 @@ enum GameState {
     AssetLoading,
     Matchmaking,
     InGame,
+    Results, // the match ended, e.g. because the opponent left
+    Rebinding, // changing the key bindings, on top of one of the others
 }
 
-// resource so we have some way to tell which player is the local 
-// player so we know what player the camera should follow
-#[derive(Resource)]
-struct LocalPlayerHandle(usize);
-
-// Our input needs to be encoded to the u8 we defined in the GgrsConfig
-// type and handed over to GGRS.
+// Our input needs to be encoded to the type we defined in the GgrsConfig
+// type and handed over to GGRS (see input::PlayerInput).
 // Define some bit mask constants to signify what bit means what:
 //const INPUT_UP: u8 = 1 << 0;
 //const INPUT_DOWN: u8 = 1 << 1;
@@ -65,54 +84,141 @@ fn main() {
             Schedule::default().with_stage(
                 "ROLLBACK_STAGE",
                 SystemStage::single_threaded()
-                    .with_system(move_players)
-                    .with_system(reload_bullet)
-                    .with_system(fire_bullets.after(move_players).after(reload_bullet)) // add our fire_bullets to our rollback stage / added explicit ordering to our rollback systems to make it deterministic
+                    .with_system(update_controls) // take this frame's inputs before anything reads them
+                    .with_system(face_opponents.after(update_controls)) // turn toward the opponent before forward and back are read
+                    .with_system(update_fighter_states.after(face_opponents)) // step every fighter's state machine before anything acts on it
+                    .with_system(resolve_throws.after(update_fighter_states)) // tech or land the throws in progress
+                    .with_system(move_players.after(resolve_throws))
+                    .with_system(reload_bullet.after(update_controls))
+                    .with_system(fire_bullets.after(limit_distance).after(reload_bullet)) // add our fire_bullets to our rollback stage / added explicit ordering to our rollback systems to make it deterministic
                     .with_system(move_bullet) // move the bullets to the right every frame after adding it to our rollback frame
-                    .with_system(kill_players.after(move_bullet).after(move_players)) // kill players at the very end. That ensures the player is destroyed as close as possible to the detection (commands are executed at the end of the stage). That way they’re destroyed before they get the chance to take more actions
-                    .with_system(spawn_floor), //adds our spawn_floor to the rollback stage
+                    .with_system(apply_jumps.after(resolve_throws)) // fighters done with their prejump take off
+                    .with_system(step_bodies.after(move_players).after(apply_jumps)) // velocity, gravity, friction, floor and walls
+                    .with_system(land_jumpers.after(step_bodies)) // fighters back on the floor land
+                    .with_system(bounce_off_walls.after(land_jumpers)) // fighters knocked into a wall bounce off it
+                    .with_system(update_pushboxes.after(bounce_off_walls)) // pushboxes follow the stance
+                    .with_system(separate_pushboxes.after(update_pushboxes)) // fighters can't stand inside each other
+                    .with_system(limit_distance.after(separate_pushboxes)) // or further apart than the screen is wide
+                    .with_system(attach_hitboxes.after(limit_distance)) // boxes follow their owners
+                    .with_system(attach_grabboxes.after(limit_distance))
+                    .with_system(resolve_grabs.after(attach_grabboxes)) // grabs go first, so a throw wins over a hit on the same frame
+                    .with_system(resolve_hits.after(attach_hitboxes).after(resolve_grabs).after(land_jumpers)) // check hitboxes against hurtboxes once everyone has moved
+                    .with_system(expire_hitboxes.after(resolve_hits)) // remove boxes whose active frames are over
+                    .with_system(perform_moves.after(resolve_hits)) // request the hitboxes of moves that reach their active frames, once landings, bounces, throws and hits have changed the fighter states
+                    .with_system(spawn_hitboxes.after(expire_hitboxes).after(perform_moves).after(fire_bullets)) // new boxes show up from the next frame on. Everything that spawns takes spawn ids in a fixed order: bullets, hitboxes, grabboxes
+                    .with_system(spawn_grabboxes.after(spawn_hitboxes))
+                    .with_system(kill_players.after(move_bullet).after(limit_distance)) // kill players at the very end. That ensures the player is destroyed as close as possible to the detection (commands are executed at the end of the stage). That way they’re destroyed before they get the chance to take more actions
+                    .with_system(sync_transforms.after(limit_distance).after(move_bullet)) // draw everything where the simulation left it
+            )
+            .with_stage(
+                "CHECKSUM_STAGE", // after ROLLBACK_STAGE, so everything spawned or despawned this frame is included
+                SystemStage::single_threaded().with_system(compute_checksum),
             ),
         )
         .register_rollback_component::<Transform>() // register the types we are interested in rolling back
+        .register_rollback_component::<Position>() // where things are in the simulation, Transform only follows it
         .register_rollback_component::<BulletReady>() // register BulletReady as a rollback type
         .register_rollback_component::<MoveDir>() // register MoveDir as a rollback type
-        // Step 07 (not finished): register Jumper as a rollback component, then add to player controls
-        //.register_rollback_component::<Jumper>()
+        .register_rollback_component::<FighterState>() // the fighter state machine has to survive rollbacks
+        .register_rollback_component::<Player>() // roll back hp as well
+        .register_rollback_component::<Controls>() // recent inputs, for finding presses and buffering them
+        .register_rollback_component::<Hitbox>()
+        .register_rollback_component::<Hurtbox>()
+        .register_rollback_component::<Grabbox>()
+        .register_rollback_component::<SpawnId>() // tells rollback entities apart the same way on every peer
+        .register_rollback_resource::<SpawnCounter>()
+        .register_rollback_resource::<FrameCount>()
+        .register_rollback_resource::<FrameChecksum>()
+        .register_rollback_component::<Jumper>() // whether a fighter is in the middle of a jump
+        .register_rollback_component::<Body>() // everything the physics step keeps between frames
+        .register_rollback_component::<Pushbox>()
         .build(&mut app);
 
-    app.add_state(GameState::AssetLoading) //initialize GameState states
-        .add_loading_state(
-            LoadingState::new(GameState::AssetLoading)
-                .with_collection::<ImageAssets>()
-                .continue_to_state(GameState::Matchmaking), // Continue Matchmaking state after loading
-        )
-        .insert_resource(ClearColor(Color::rgb(0.53, 0.53, 0.53)))
+    app.insert_resource(ClearColor(Color::rgb(0.53, 0.53, 0.53)))
         // .insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities) // see types of gotchas through this special resource that was used to figure out the ordering between reloading & firing
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             window: WindowDescriptor {
+                title: "Approaching Storms".to_string(),
                 // fill the entire browser window
+                #[cfg(target_arch = "wasm32")]
                 fit_canvas_to_parent: true,
+                // browsers decide the canvas size, natively we open a
+                // reasonably sized window ourselves
+                #[cfg(not(target_arch = "wasm32"))]
+                width: 1280.,
+                #[cfg(not(target_arch = "wasm32"))]
+                height: 720.,
                 ..default()
             },
             ..default()
-        }))
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::default()) // register rapier plugin with our application
+        }));
+
+    // the launch options decide whether we look for an opponent at all, so
+    // read them before setting up the states (but after DefaultPlugins, so
+    // warnings about bad options get logged)
+    let args = Args::get();
+    let after_loading = if args.plays_locally() {
+        GameState::InGame // local versus and synctests don't need matchmaking
+    } else {
+        GameState::Matchmaking
+    };
+
+    app.add_state(GameState::AssetLoading) //initialize GameState states
+        .add_loading_state(
+            LoadingState::new(GameState::AssetLoading)
+                .with_collection::<ImageAssets>()
+                .with_collection::<MoveAssets>() // frame data for every move
+                .with_collection::<FontAssets>()
+                .continue_to_state(after_loading), // Continue Matchmaking state after loading
+        )
+        .add_event::<HitboxSpawnEvent>() // sent inside the rollback stage to throw out hitboxes and grabboxes
+        .add_event::<GrabboxSpawnEvent>()
+        .add_asset::<MoveList>() // let the asset server load fighter.moves.ron files
+        .init_asset_loader::<MoveListLoader>()
+        .insert_resource(ShowBoxes(args.boxes))
+        .insert_resource(args.motion_windows.clone()) // how quickly special move motions have to be done
+        .insert_resource(args)
+        .insert_resource(Bindings::load()) // the keys the players picked last time
+        .init_resource::<RebindMenu>()
+        .init_resource::<SpawnCounter>()
+        .init_resource::<FrameCount>()
+        .init_resource::<FrameChecksum>()
+        .init_resource::<ChecksumHistory>()
+        .init_resource::<ConnectionStatus>()
+        .add_startup_system(spawn_stage) // the floor, the walls and the camera
         .add_system_set( // divide our systems into system sets for the appropriate states
             SystemSet::on_enter(GameState::Matchmaking)
                 .with_system(start_matchbox_socket) // adds the start_matchbox_socket system
-                .with_system(setup), //setup system that initializes a camera and a player sprite
         )
         .add_system_set(SystemSet::on_update(GameState::Matchmaking).with_system(wait_for_players)) // adds the wait_for_players system
+        .add_system_set(SystemSet::on_enter(GameState::InGame).with_system(start_local_session)) // local versus / synctest sessions start right away
         .add_system_set(SystemSet::on_enter(GameState::InGame).with_system(spawn_players)) //adds the spawn_player system
-        .add_system_set(SystemSet::on_update(GameState::InGame).with_system(camera_follow)) // adds the camera_follow system
-        .add_system_set(SystemSet::on_enter(GameState::InGame).with_system(spawn_floor))
+        .add_system_set(
+            SystemSet::on_update(GameState::InGame)
+                .with_system(frame_fighters) // keep both fighters in the picture
+                .with_system(toggle_boxes) // F2
+                .with_system(show_boxes.after(toggle_boxes))
+                .with_system(draw_pushboxes.after(toggle_boxes))
+                .with_system(detect_desyncs) // compare checksums with the other peers
+                .with_system(handle_session_events) // react to peers synchronizing, stalling and leaving
+                .with_system(update_connection_overlay.after(handle_session_events)),
+        )
+        .add_system_set(SystemSet::on_enter(GameState::InGame).with_system(spawn_connection_overlay))
+        .add_system_set(SystemSet::on_exit(GameState::InGame).with_system(leave_match)) // drop the session so the next match starts fresh
+        .add_system_set(SystemSet::on_enter(GameState::Results).with_system(show_results))
+        .add_system_set(SystemSet::on_update(GameState::Results).with_system(leave_results))
+        .add_system_set(SystemSet::on_exit(GameState::Results).with_system(hide_results))
+        .add_system(open_rebind_menu) // F1
+        .add_system_set(SystemSet::on_enter(GameState::Rebinding).with_system(show_rebind_menu))
+        .add_system_set(
+            SystemSet::on_update(GameState::Rebinding)
+                .with_system(update_rebind_menu)
+                .with_system(draw_rebind_menu.after(update_rebind_menu)),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Rebinding).with_system(hide_rebind_menu)) // also saves the bindings
         .run();
 }
 
-// adding a constant that defines our map width and height
-const MAP_SIZE: i32 = 41;
-const GRID_WIDTH: f32 = 0.05;
-
 // An asset collection for our images
 #[derive(AssetCollection, Resource)]
 struct ImageAssets {
@@ -120,88 +226,37 @@ struct ImageAssets {
     bullet: Handle<Image>,
 }
 
-// add struct RigidBodyBundle
-
-// code for setup system that initializes a camera and a player sprite
-fn setup(mut commands: Commands) {
-    // spawn the sprites
-    // Horizontal lines
-    for i in 0..=MAP_SIZE {
-        commands.spawn(SpriteBundle {
-            transform: Transform::from_translation(Vec3::new(
-                0.,
-                i as f32 - MAP_SIZE as f32 / 2.,
-                0.,
-            )),
-            sprite: Sprite {
-                color: Color::rgb(0.27, 0.27, 0.27),
-                custom_size: Some(Vec2::new(MAP_SIZE as f32, GRID_WIDTH)),
-                ..default()
-            },
-            ..default()
-        });
-    }
-
-    // Vertical lines
-    for i in 0..=MAP_SIZE {
-        commands.spawn(SpriteBundle {
-            transform: Transform::from_translation(Vec3::new(
-                i as f32 - MAP_SIZE as f32 / 2.,
-                0.,
-                0.,
-            )),
-            sprite: Sprite {
-                color: Color::rgb(0.27, 0.27, 0.27),
-                custom_size: Some(Vec2::new(GRID_WIDTH, MAP_SIZE as f32)),
-                ..default()
-            },
-            ..default()
-        });
-    }
-
-    /* STEP 01 */
-    let rigid_body = RigidBody::default();
-    let collider = Collider::cuboid(1.0, 2.0);
-
-
-    commands.spawn(rigid_body); //RigidBodyBundle
-    commands.spawn(collider); //ColliderBundle
+#[derive(AssetCollection, Resource)]
+struct FontAssets {
+    #[asset(path = "fonts/FiraSans-Bold.ttf")]
+    font: Handle<Font>,
+}
 
-    let mut camera_bundle = Camera2dBundle::default();
-    camera_bundle.projection.scaling_mode = ScalingMode::FixedVertical(10.); //fixed vertical size of 10
-    commands.spawn(camera_bundle);
+// the frame data of the fighters' moves
+#[derive(AssetCollection, Resource)]
+struct MoveAssets {
+    #[asset(path = "fighter.moves.ron")]
+    moves: Handle<MoveList>,
 }
 
 // adds players
-fn spawn_players(mut commands: Commands, mut rip: ResMut<RollbackIdProvider>) {
+fn spawn_players(mut commands: Commands, mut rip: ResMut<RollbackIdProvider>, mut spawns: ResMut<SpawnCounter>) {
     info!("Spawning players");
 
     // Player 1
     commands.spawn((
-        Player { handle: 0, speed: 4., facing_direction: GameDirection::Right, hp: 100.,}, // adds player component to player entity / added speed, facing_direction, and hp (STEP 05)
-        Jumper {jump_impulse: 14., is_jumping: false,},
+        Player { handle: 0, facing_direction: GameDirection::Right, hp: Fixed::from_int(100),}, // adds player component to player entity / added facing_direction and hp (STEP 05)
+        Jumper { jump_impulse: Fixed::milli(300), ..default() },
         BulletReady(true), //add BulletReady rollback type when we spawn player
-        MoveDir(-Vec2::X), // keep track of the player direction
+        MoveDir(-FixedVec2::X), // keep track of the player direction
+        FighterState::default(),
+        Controls::default(),
+        Body::grounded(FRICTION),
+        STANDING_PUSHBOX, // a bit narrower than the sprite, see update_pushboxes
+        Hurtbox { width: Fixed::ONE, height: Fixed::ONE, offset: FixedVec2::ZERO }, // same size as the player sprite
         Rollback::new(rip.next_id()), // adds rollback component to player entity
-
-        /*
-        RigidBodyBundle {
-            mass_properties: RigidBodyMassPropsFlags::ROTATION_LOCKED.into(),
-            activation: RigidBodyActivation::cannot_sleep(),
-            ccd: RigidBodyCcd { ccd_enabled: true, ..Default::default() },
-            ..Default::default()
-        },
-
-        ColliderBundle {
-            shape: ColliderShape::cuboid(0.5, 0.5),
-            flags: ColliderFlags {
-            active_events: ActiveEvents::CONTACT_EVENTS,
-            ..Default::default()
-            },
-            ..Default::default()
-        },
-        */
-
+        spawns.next(),
+        Position::new(Fixed::from_int(-2), FLOOR_Y),
         SpriteBundle {
             transform: Transform::from_translation(Vec3::new(-2., 0., 100.)), // Instead of moving the background forward, we’ll move the players closer to the camera because of z
             sprite: Sprite {
@@ -211,41 +266,25 @@ fn spawn_players(mut commands: Commands, mut rip: ResMut<RollbackIdProvider>) {
             },
             ..default()
         },
-        
-    )
-)
-        // STEP 02: Rigid-Bodies & Colliders
-        .insert(TransformBundle::from(Transform::from_xyz(0.0, 5.0, 0.0)))
-        .insert(Velocity {
-            linvel: Vec2::new(1.0, 2.0),
-            angvel: 0.2
-        })
-
-        // STEP 04: gravity and control
-        .insert(Jumper {
-            jump_impulse: 14.,
-            is_jumping: false,
-        })
-
-        .insert(GravityScale(0.5))
-        .insert(Sleeping::disabled())
-        .insert(Ccd::enabled())
-
-        .insert(Sensor)
-        .insert(TransformBundle::from(Transform::from_xyz(2.0, 0.0, 0.0)))
-        .insert(Friction::coefficient(0.7))
-        .insert(Restitution::coefficient(0.3))
-        .insert(ColliderMassProperties::Density(2.0))
-
-;
+    ))
+    .with_children(|fighter| {
+        fighter.spawn(pushbox_sprite());
+    });
 
     // Player 2
     commands.spawn((
-        Player { handle: 1, speed: 4., facing_direction: GameDirection::Left, hp: 100., }, // adds player component to player entity
-        Jumper {jump_impulse: 14., is_jumping: false,},
+        Player { handle: 1, facing_direction: GameDirection::Left, hp: Fixed::from_int(100), }, // adds player component to player entity
+        Jumper { jump_impulse: Fixed::milli(300), ..default() },
         BulletReady(true), //add BulletReady rollback type when we spawn player
-        MoveDir(Vec2::X), // keep track of the player direction
+        MoveDir(FixedVec2::X), // keep track of the player direction
+        FighterState::default(),
+        Controls::default(),
+        Body::grounded(FRICTION),
+        STANDING_PUSHBOX, // a bit narrower than the sprite, see update_pushboxes
+        Hurtbox { width: Fixed::ONE, height: Fixed::ONE, offset: FixedVec2::ZERO }, // same size as the player sprite
         Rollback::new(rip.next_id()), // adds rollback component to player entity
+        spawns.next(),
+        Position::new(Fixed::from_int(2), FLOOR_Y),
         SpriteBundle {
             transform: Transform::from_translation(Vec3::new(2., 0., 100.)), // Instead of moving the background forward, we’ll move the players closer to the camera because of z
             sprite: Sprite {
@@ -256,16 +295,15 @@ fn spawn_players(mut commands: Commands, mut rip: ResMut<RollbackIdProvider>) {
             ..default()
         },
     ))
-    //.insert(rigid_body)
-    //.insert(collider)
-    //.insert(RigidBodyPositionSync::Discrete)
-    ;
+    .with_children(|fighter| {
+        fighter.spawn(pushbox_sprite());
+    });
 }
 
 // A system that creates the socket which connects to the Matchbox 
 // server and establishes direct connections to other clients.
-fn start_matchbox_socket(mut commands: Commands) {
-    let room_url = "ws://127.0.0.1:3536/extreme_bevy?next=2";
+fn start_matchbox_socket(mut commands: Commands, args: Res<Args>) {
+    let room_url = args.room_url();
     info!("connecting to matchbox server: {:?}", room_url);
     let (socket, message_loop) = WebRtcSocket::new(room_url);
 
@@ -285,6 +323,7 @@ fn wait_for_players(
     mut commands: Commands,
     mut session: ResMut<Session>,
     mut state: ResMut<State<GameState>>, // make sure that we actually enter the InGame state in wait_for_players when we start the GGRS session
+    args: Res<Args>,
 ) {
     let Some(socket) = &mut session.socket else {
         // If there is no socket we've already started the game
@@ -295,41 +334,100 @@ fn wait_for_players(
     socket.accept_new_connections();
     let players = socket.players();
 
-    let num_players = 2;
-    if players.len() < num_players {
+    if players.len() < args.players {
         return; // wait for more players
     }
 
     info!("All peers have joined, going in-game");
 
     // create a GGRS P2P session
-    let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
-        .with_num_players(num_players) // how many players will there be
-        .with_input_delay(2); //input delay
-
-    // adds players to the session, where we just need to assign a 
-    // handle to each of them. We simply assign integers in the order 
-    // that they arrive.
-    for (i, player) in players.into_iter().enumerate() {
-        if player == PlayerType::Local { // inserting the LocalPlayerHandle into wait_for_players
-            commands.insert_resource(LocalPlayerHandle(i));
+    let session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(FIGHTERS) // how many players will there be
+        .with_max_prediction_window(MAX_PREDICTION)
+        .with_input_delay(args.input_delay.unwrap_or(2)); //input delay
+
+    // move the socket out of the resource (required because GGRS takes ownership of it)
+    // and share it with the desync detection
+    let socket = SharedSocket::new(session.socket.take().unwrap());
+    commands.insert_resource(socket.clone());
+
+    // Every peer sees the players in the same order. The first two fight,
+    // with their place in that order as their handle, and everyone after
+    // them watches. Spectators get the confirmed inputs from the first
+    // player, the host, so only the host sends to them
+    let local = players
+        .iter()
+        .position(|player| matches!(player, PlayerType::Local))
+        .expect("the local player is always in the list");
+    let ggrs_session = if local < FIGHTERS {
+        let mut session_builder = session_builder;
+        for (i, player) in players.into_iter().enumerate() {
+            let player = match player {
+                PlayerType::Remote(address) if i >= FIGHTERS => {
+                    if local != 0 {
+                        continue;
+                    }
+                    PlayerType::Spectator(address)
+                }
+                player => player,
+            };
+            session_builder = session_builder
+                .add_player(player, i)
+                .expect("failed to add player");
         }
+        // start the GGRS session
+        let ggrs_session = session_builder // creating a bevy_ggrs session using its SessionBuilder
+            .start_p2p_session(socket)
+            .expect("failed to start session");
+        bevy_ggrs::Session::P2PSession(ggrs_session)
+    } else {
+        let PlayerType::Remote(host) = players[0].clone() else {
+            unreachable!("the local player is a spectator");
+        };
+        info!("watching the match hosted by {host}");
+        bevy_ggrs::Session::SpectatorSession(session_builder.start_spectator_session(host, socket))
+    };
 
+    commands.insert_resource(ggrs_session);
+    state.set(GameState::InGame).unwrap();
+}
+
+// Both local modes run both players on this machine through a
+// SyncTestSession, so no matchbox server is needed.
+//
+// For local versus the check distance is 0, which makes it a plain local
+// session without any rollbacks. A synctest instead rolls back
+// `check_distance` frames every frame and resimulates them. If the
+// resimulated state doesn't hash the same as the first time, GGRS reports
+// the frame where the checksums mismatched, so any non-deterministic
+// system in the rollback stage shows up right away.
+fn start_local_session(mut commands: Commands, args: Res<Args>) {
+    if !args.plays_locally() {
+        return;
+    }
+
+    let check_distance = args.synctest.unwrap_or(0);
+    let default_delay = if args.synctest.is_some() { 2 } else { 0 };
+    let input_delay = args.input_delay.unwrap_or(default_delay);
+    info!("starting local session with check distance {check_distance}");
+
+    let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(FIGHTERS)
+        .with_max_prediction_window(MAX_PREDICTION)
+        .with_check_distance(check_distance)
+        .with_input_delay(input_delay);
+
+    for i in 0..FIGHTERS {
         session_builder = session_builder
-            .add_player(player, i)
+            .add_player(PlayerType::Local, i)
             .expect("failed to add player");
     }
 
-    // move the socket out of the resource (required because GGRS takes ownership of it)
-    let socket = session.socket.take().unwrap();
-
-    // start the GGRS session
-    let ggrs_session = session_builder // creating a bevy_ggrs session using its SessionBuilder
-        .start_p2p_session(socket)
+    let ggrs_session = session_builder
+        .start_synctest_session()
         .expect("failed to start session");
 
-    commands.insert_resource(bevy_ggrs::Session::P2PSession(ggrs_session));
-    state.set(GameState::InGame).unwrap();
+    commands.insert_resource(bevy_ggrs::Session::SyncTestSession(ggrs_session));
 }
 
 // makes player move on keyboard / samples the keyboard and moves 
@@ -337,30 +435,28 @@ fn wait_for_players(
 // STEP 06: moving players
 fn move_players(
     inputs: Res<PlayerInputs<GgrsConfig>>,
-    mut player_query: Query<(&mut Transform, &mut MoveDir, &Player, &mut Jumper, &mut Velocity,)>,
+    mut player_query: Query<(&mut MoveDir, &Player, &FighterState, &mut Body)>,
 ) {
-    for (mut transform, mut move_direction, player, jumper, velocity) in player_query.iter_mut() { // Step 07: adding jumper / velocity component
+    for (mut move_direction, player, fighter, mut body) in player_query.iter_mut() {
         let (input, _) = inputs[player.handle];
-        let direction = direction(input);
+        let direction = input.horizontal(1) as i32;
 
-        if direction == Vec2::ZERO {
-            continue;
+        if direction != 0 {
+            move_direction.0 = FixedVec2::X * Fixed::from_int(direction);
         }
 
-        move_direction.0 = direction;
-
-         // STEP 08: Jumping / Velocity code (nothing here yet)
-
-        // our player shouldn't be able to move out of the map
-        let move_speed = 0.13;
-        let move_delta = direction * move_speed;
-
-        let old_pos = transform.translation.xy();
-        let limit = Vec2::splat(MAP_SIZE as f32 / 2. - 0.5); // makes sure no players goes away from the map
-        let new_pos = (old_pos + move_delta).clamp(-limit, limit);
-
-        transform.translation.x = new_pos.x;
-        transform.translation.y = new_pos.y;
+        // walking and dashing fighters set their own speed, dashes keep
+        // going in a fixed direction even after the stick is released.
+        // Standing fighters and both sides of a throw stay where they are,
+        // everyone else slides out whatever push they got (see step_bodies)
+        let forward = forward(player.facing_direction) as i32;
+        body.velocity.x = match fighter.state {
+            PlayerState::Walk | PlayerState::WalkB => WALK_SPEED * direction,
+            PlayerState::Dash => DASH_SPEED * forward,
+            PlayerState::DashB => -DASH_SPEED * forward,
+            _ if fighter.is_neutral() || fighter.in_throw() => Fixed::ZERO,
+            _ => continue,
+        };
     }
 }
 
@@ -386,13 +482,10 @@ fn move_players(
 // Finally, we create our Session resource and initialize it with our 
 // newly created socket so we can access the socket from other systems.
 
-fn reload_bullet(
-    inputs: Res<PlayerInputs<GgrsConfig>>,
-    mut query: Query<(&mut BulletReady, &Player)>,
-) {
-    for (mut can_fire, player) in query.iter_mut() {
-        let (input, _) = inputs[player.handle];
-        if !fire(input) {
+// a fired bullet has to be released before the next one can come out
+fn reload_bullet(mut query: Query<(&mut BulletReady, &Controls)>) {
+    for (mut can_fire, controls) in query.iter_mut() {
+        if controls.released().fire() {
             can_fire.0 = true;
         }
     }
@@ -402,25 +495,30 @@ fn reload_bullet(
 // bullet image. Let’s make a new system for this
 fn fire_bullets(
     mut commands: Commands,
-    inputs: Res<PlayerInputs<GgrsConfig>>,
     images: Res<ImageAssets>,
-    mut player_query: Query<(&Transform, &Player, &mut BulletReady, &MoveDir)>,
+    mut player_query: Query<(&Position, &Player, &Controls, &mut BulletReady, &MoveDir)>,
     mut rip: ResMut<RollbackIdProvider>, // make sure our bullets are rolled back
+    mut spawns: ResMut<SpawnCounter>,
 ) {
-    for (transform, player, mut bullet_ready, move_dir) in player_query.iter_mut() {
-        // spawn our bullet sprite
-        let (input, _) = inputs[player.handle];
-        // check whether a bullet is ready
-        if fire(input) && bullet_ready.0 {
-            let player_pos = transform.translation.xy(); // moving the bullet a little bit away from the player when spawning it
-            let pos = player_pos + move_dir.0 * PLAYER_RADIUS + BULLET_RADIUS; // moving the bullet a little bit away from the player when spawning it
+    // fire in handle order so the bullets get their spawn ids in the
+    // same order on every peer
+    let mut players: Vec<_> = player_query.iter_mut().collect();
+    players.sort_by_key(|(_, player, ..)| player.handle);
+
+    for (player_pos, _, controls, mut bullet_ready, move_dir) in players {
+        // spawn our bullet sprite on the frame the button goes down,
+        // if a bullet is ready
+        if controls.pressed().fire() && bullet_ready.0 {
+            let pos = player_pos.0 + move_dir.0 * (PLAYER_RADIUS + BULLET_RADIUS); // moving the bullet a little bit away from the player when spawning it
             commands.spawn((
                 Bullet, // now that we have a player direction, we can copy it to our bullets in fire_bullets
                 Rollback::new(rip.next_id()), // make sure our bullets are rolled back
+                spawns.next(),
                 *move_dir,
+                Position(pos),
                 SpriteBundle {
-                    transform: Transform::from_translation(pos.extend(200.))
-                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, move_dir.0)), // moving the bullet a little bit away from the player when spawning it
+                    transform: Transform::from_translation(pos.to_vec2().extend(200.))
+                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, move_dir.0.to_vec2())), // moving the bullet a little bit away from the player when spawning it
                     texture: images.bullet.clone(),
                     sprite: Sprite {
                         custom_size: Some(Vec2::new(0.3, 0.1)),
@@ -435,15 +533,21 @@ fn fire_bullets(
 }
 
 // write the system
-fn move_bullet(mut query: Query<(&mut Transform, &MoveDir), With<Bullet>>) {
-    for (mut transform, dir) in query.iter_mut() {
-        let delta = (dir.0 * 0.35).extend(0.);
-        transform.translation += delta;
+fn move_bullet(mut query: Query<(&mut Position, &MoveDir), With<Bullet>>) {
+    for (mut position, dir) in query.iter_mut() {
+        position.0 += dir.0 * BULLET_SPEED;
     }
 }
 
-const PLAYER_RADIUS: f32 = 0.5;
-const BULLET_RADIUS: f32 = 0.025;
+// how many players fight in a match, see spawn_players. Any further peers
+// in the room watch
+const FIGHTERS: usize = 2;
+const PLAYER_RADIUS: Fixed = Fixed::milli(500);
+const WALK_SPEED: Fixed = Fixed::milli(130);
+const DASH_SPEED: Fixed = Fixed::milli(250);
+const FRICTION: Fixed = Fixed::milli(800); // share of a push fighters keep each frame
+const BULLET_RADIUS: Fixed = Fixed::milli(25);
+const BULLET_SPEED: Fixed = Fixed::milli(350);
 
 
 // A player should die if the bullet overlaps the player. In other 
@@ -451,79 +555,17 @@ const BULLET_RADIUS: f32 = 0.025;
 // sum of their radii
 fn kill_players(
     mut commands: Commands,
-    player_query: Query<(Entity, &Transform), (With<Player>, Without<Bullet>)>,
-    bullet_query: Query<&Transform, With<Bullet>>,
+    player_query: Query<(Entity, &Position), With<Player>>,
+    bullet_query: Query<&Position, With<Bullet>>,
 ) {
-    for (player, player_transform) in player_query.iter() {
-        for bullet_transform in bullet_query.iter() {
-            let distance = Vec2::distance(
-                player_transform.translation.xy(),
-                bullet_transform.translation.xy(),
-            );
-            if distance < PLAYER_RADIUS + BULLET_RADIUS {
+    for (player, player_pos) in player_query.iter() {
+        for bullet_pos in bullet_query.iter() {
+            // compare squared distances, no square roots needed
+            let offset = player_pos.0 - bullet_pos.0;
+            let reach = PLAYER_RADIUS + BULLET_RADIUS;
+            if offset.x * offset.x + offset.y * offset.y < reach * reach {
                 commands.entity(player).despawn_recursive();
             }
         }
     }
 }
-
-// declare our inputs
-fn camera_follow(
-    player_handle: Option<Res<LocalPlayerHandle>>,
-    player_query: Query<(&Player, &Transform)>,
-    mut camera_query: Query<&mut Transform, (With<Camera>, Without<Player>)>,
-) {
-    // If there is no player handle yet, we simply return early
-    let player_handle = match player_handle {
-        Some(handle) => handle.0,
-        None => return, // Session hasn't started yet
-    };
-
-    // loop through and find the player position
-    for (player, player_transform) in player_query.iter() {
-        if player.handle != player_handle {
-            continue;
-        }
-
-        let pos = player_transform.translation;
-
-        for mut transform in camera_query.iter_mut() {
-            transform.translation.x = pos.x;
-            transform.translation.y = pos.y;
-        }
-    }
-}
-
-/* STEP 03: Make Floor ( let mut rigid_body = RigidBody::default();
-    let mut collider = Collider::cuboid(1.0, 2.0);) */
-fn spawn_floor(mut commands: Commands) {
-// mut materials: ResMut<Assets<ColorMaterial>>
-
-    commands
-    .spawn(Collider::cuboid(500.0, 50.0))
-    .insert(Name::new("Ground"))
-    .insert(TransformBundle::from(Transform::from_xyz(0.0, -100.0, 0.0)));
-    /* 
-    let width = 10.;
-    let height = 1.;
-    
-    let rigid_body02 = RigidBody {
-        position: Vec2::new(0.0, -2.).into(),
-        body_type: RigidBody::Fixed,
-        ..Default::default()
-    };
-    let collider02 = Collider {
-        shape: ColliderShape::cuboid(width / 2., height / 2.),
-        ..Default::default()
-    };
-    commands
-        .spawn(SpriteBundle {
-            material: materials.add(Color::rgb(0.7, 0.7, 0.7).into()),
-            sprite: Sprite::new(Vec2::new(width, height)),
-            ..Default::default()
-        })
-        .insert(rigid_body02)
-        .insert(collider02)
-        .insert(RigidBodyPositionSync::Discrete);
-    */
-}
\ No newline at end of file
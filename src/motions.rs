@@ -0,0 +1,308 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::components::*;
+use crate::input::PlayerInput;
+
+// The joystick motions special moves are performed with, in numpad
+// notation as seen by a fighter facing right: 6 is forward, 4 is back,
+// 2 is down, 5 is neutral. They are mirrored for a fighter facing left.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub enum Motion {
+    QuarterCircleForward, // 236
+    QuarterCircleBack,    // 214
+    DragonPunch,          // 623
+    HalfCircleForward,    // 41236
+    FullCircle,           // 360: forward, down, back and up in any order
+    DashForward,          // 66, a double tap
+    DashBack,             // 44
+    Charge,               // [4]6: hold back for a while, then forward
+}
+
+// how many frames each motion may take from its first to its last
+// direction. A bit more generous for the longer motions. Each of them can
+// be changed with a launch option (see Args), but only for local sessions
+#[derive(Resource, Clone, PartialEq, Eq, Debug)]
+pub struct MotionWindows {
+    pub quarter_circle: usize,
+    pub dragon_punch: usize,
+    pub half_circle: usize,
+    pub full_circle: usize,
+    pub dash: usize,
+    // frames back has to be held before a charge move is ready
+    pub charge: u32,
+    // frames a ready charge waits for the forward press after back is let go
+    pub charge_release: u32,
+}
+
+impl Default for MotionWindows {
+    fn default() -> Self {
+        Self {
+            quarter_circle: 12,
+            dragon_punch: 14,
+            half_circle: 20,
+            full_circle: 30,
+            dash: 12,
+            charge: 40,
+            charge_release: 10,
+        }
+    }
+}
+
+// the order motions are checked in when a button is pressed. Longer
+// motions contain shorter ones (41236 ends in 236, 623 passes through 23),
+// so they go first
+pub const MOTION_PRIORITY: [Motion; 6] = [
+    Motion::FullCircle,
+    Motion::HalfCircleForward,
+    Motion::DragonPunch,
+    Motion::QuarterCircleForward,
+    Motion::QuarterCircleBack,
+    Motion::Charge,
+];
+
+// the numpad digit of an input, turned around for a fighter facing left
+pub fn numpad(input: PlayerInput, forward: i8) -> u8 {
+    let x = input.horizontal(forward);
+    let y = if input.up() {
+        1
+    } else if input.down() {
+        -1
+    } else {
+        0
+    };
+    (5 + x + 3 * y) as u8
+}
+
+fn is_back(digit: u8) -> bool {
+    matches!(digit, 1 | 4 | 7)
+}
+
+// walks back through the last `window` frames looking for the digits of
+// `sequence` in reverse order. Other directions in between are fine, so
+// slightly sloppy motions still count
+fn performed_sequence(controls: &Controls, forward: i8, sequence: &[u8], window: usize) -> bool {
+    let mut remaining = sequence.len();
+    for frames_ago in 0..window.min(INPUT_HISTORY) {
+        if remaining == 0 {
+            break;
+        }
+        if numpad(controls.held_at(frames_ago), forward) == sequence[remaining - 1] {
+            remaining -= 1;
+        }
+    }
+    remaining == 0
+}
+
+// forward, down, back and up all show up within `window` frames
+fn performed_circle(controls: &Controls, forward: i8, window: usize) -> bool {
+    let mut seen = [false; 4];
+    for frames_ago in 0..window.min(INPUT_HISTORY) {
+        match numpad(controls.held_at(frames_ago), forward) {
+            6 => seen[0] = true,
+            2 => seen[1] = true,
+            4 => seen[2] = true,
+            8 => seen[3] = true,
+            _ => {}
+        }
+    }
+    seen.iter().all(|&seen| seen)
+}
+
+// `digit` was freshly pressed this frame, and already once before within
+// `window` frames. Both taps have to be fresh presses, so walking,
+// letting go and walking on doesn't turn into a dash
+fn tapped_twice(controls: &Controls, forward: i8, digit: u8, window: usize) -> bool {
+    let tapped = |frames_ago: usize| {
+        numpad(controls.held_at(frames_ago), forward) == digit
+            && numpad(controls.held_at(frames_ago + 1), forward) != digit
+    };
+    tapped(0) && (2..window.min(INPUT_HISTORY - 1)).any(tapped)
+}
+
+impl MotionWindows {
+    // whether the fighter finished the motion within its window. Charge
+    // moves also need the charge the fighter built up (see track_charge)
+    pub fn performed(&self, motion: Motion, controls: &Controls, fighter: &FighterState, forward: i8) -> bool {
+        match motion {
+            Motion::QuarterCircleForward => performed_sequence(controls, forward, &[2, 3, 6], self.quarter_circle),
+            Motion::QuarterCircleBack => performed_sequence(controls, forward, &[2, 1, 4], self.quarter_circle),
+            Motion::DragonPunch => performed_sequence(controls, forward, &[6, 2, 3], self.dragon_punch),
+            Motion::HalfCircleForward => performed_sequence(controls, forward, &[4, 1, 2, 3, 6], self.half_circle),
+            Motion::FullCircle => performed_circle(controls, forward, self.full_circle),
+            Motion::DashForward => tapped_twice(controls, forward, 6, self.dash),
+            Motion::DashBack => tapped_twice(controls, forward, 4, self.dash),
+            Motion::Charge => fighter.charge_window > 0 && numpad(controls.current(), forward) == 6,
+        }
+    }
+}
+
+impl FighterState {
+    // count how long back has been held. Letting go after a full charge
+    // keeps the charge around for a few frames, so it can be released
+    // with a forward press
+    pub fn track_charge(&mut self, input: PlayerInput, forward: i8, windows: &MotionWindows) {
+        self.charge_window = self.charge_window.saturating_sub(1);
+        if is_back(numpad(input, forward)) {
+            self.charge += 1;
+        } else {
+            if self.charge >= windows.charge {
+                self.charge_window = windows.charge_release;
+            }
+            self.charge = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Button;
+
+    // the input for a numpad digit as held on the stick, not mirrored for
+    // the way the fighter faces
+    fn stick(digit: u8) -> PlayerInput {
+        let mut input = PlayerInput::default();
+        let x = (digit as i8 - 1) % 3 - 1;
+        let y = (digit as i8 - 1) / 3 - 1;
+        if x > 0 {
+            input.set(Button::Right);
+        } else if x < 0 {
+            input.set(Button::Left);
+        }
+        if y > 0 {
+            input.set(Button::Up);
+        } else if y < 0 {
+            input.set(Button::Down);
+        }
+        input
+    }
+
+    // controls that held these digits on consecutive frames, the last one
+    // being the current frame
+    fn history(digits: &[u8]) -> Controls {
+        let mut controls = Controls::default();
+        for (frame, &digit) in digits.iter().enumerate() {
+            controls.head = (frame + 1) % INPUT_HISTORY;
+            controls.held[controls.head] = stick(digit);
+        }
+        controls
+    }
+
+    fn performed(motion: Motion, digits: &[u8], forward: i8) -> bool {
+        let fighter = FighterState::default();
+        MotionWindows::default().performed(motion, &history(digits), &fighter, forward)
+    }
+
+    #[test]
+    fn quarter_circle_forward_is_mirrored_when_facing_left() {
+        // down, down-left, left on the stick
+        let digits = [5, 2, 1, 4];
+        assert!(performed(Motion::QuarterCircleForward, &digits, -1));
+        assert!(!performed(Motion::QuarterCircleBack, &digits, -1));
+        assert!(performed(Motion::QuarterCircleBack, &digits, 1));
+        assert!(!performed(Motion::QuarterCircleForward, &digits, 1));
+    }
+
+    #[test]
+    fn sloppy_quarter_circle_still_counts() {
+        assert!(performed(Motion::QuarterCircleForward, &[2, 2, 5, 3, 3, 6], 1));
+    }
+
+    #[test]
+    fn quarter_circle_has_to_fit_its_window() {
+        let mut digits = vec![2];
+        digits.extend([5; 12]);
+        digits.extend([3, 6]);
+        assert!(!performed(Motion::QuarterCircleForward, &digits, 1));
+    }
+
+    #[test]
+    fn dragon_punch_is_not_a_quarter_circle() {
+        let digits = [6, 3, 2, 3];
+        assert!(performed(Motion::DragonPunch, &digits, 1));
+        assert!(!performed(Motion::QuarterCircleForward, &digits, 1));
+    }
+
+    #[test]
+    fn half_circle_needs_every_direction() {
+        assert!(performed(Motion::HalfCircleForward, &[4, 1, 2, 3, 6], 1));
+        assert!(!performed(Motion::HalfCircleForward, &[4, 2, 6], 1));
+    }
+
+    #[test]
+    fn full_circle_needs_all_four_directions() {
+        assert!(performed(Motion::FullCircle, &[6, 3, 2, 1, 4, 7, 8], 1));
+        assert!(!performed(Motion::FullCircle, &[6, 3, 2, 1, 4], 1));
+    }
+
+    #[test]
+    fn quick_double_tap_dashes() {
+        assert!(performed(Motion::DashForward, &[5, 6, 5, 6], 1));
+        assert!(performed(Motion::DashBack, &[5, 4, 5, 4], 1));
+        // facing left, tapping right twice dashes back
+        assert!(performed(Motion::DashBack, &[5, 6, 5, 6], -1));
+    }
+
+    #[test]
+    fn walking_after_letting_go_is_not_a_dash() {
+        let mut digits = vec![5];
+        digits.extend([6; 20]);
+        digits.extend([5, 6]);
+        assert!(!performed(Motion::DashForward, &digits, 1));
+    }
+
+    #[test]
+    fn holding_forward_is_not_a_dash() {
+        assert!(!performed(Motion::DashForward, &[5, 6, 6, 6], 1));
+    }
+
+    // feeds the digits through track_charge one frame at a time and
+    // checks for a charge move on the last one
+    fn charged(digits: &[u8], forward: i8) -> bool {
+        let windows = MotionWindows::default();
+        let mut fighter = FighterState::default();
+        for &digit in digits {
+            fighter.track_charge(stick(digit), forward, &windows);
+        }
+        windows.performed(Motion::Charge, &history(digits), &fighter, forward)
+    }
+
+    #[test]
+    fn full_charge_released_into_forward() {
+        let mut digits = vec![4; 40];
+        digits.push(6);
+        assert!(charged(&digits, 1));
+    }
+
+    #[test]
+    fn full_charge_survives_a_short_release() {
+        let mut digits = vec![4; 40];
+        digits.extend([5, 6]);
+        assert!(charged(&digits, 1));
+    }
+
+    #[test]
+    fn short_charge_does_nothing() {
+        let mut digits = vec![4; 39];
+        digits.push(6);
+        assert!(!charged(&digits, 1));
+    }
+
+    #[test]
+    fn charge_runs_out_after_its_release_window() {
+        let mut digits = vec![4; 40];
+        digits.extend([5; 10]);
+        digits.push(6);
+        assert!(!charged(&digits, 1));
+    }
+
+    #[test]
+    fn charge_is_mirrored_when_facing_left() {
+        // back is right on the stick
+        let mut digits = vec![6; 40];
+        digits.push(4);
+        assert!(charged(&digits, -1));
+        assert!(!charged(&digits, 1));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, Error, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::Deserialize;
+
+use crate::checksum::FrameCount;
+use crate::components::*;
+use crate::fixed::*;
+use crate::hitboxes::{GrabboxSpawnEvent, HitboxSpawnEvent};
+use crate::motions::Motion;
+use crate::MoveAssets;
+
+// one rectangle of a move. It comes out `start` frames after the move's
+// first active frame and stays out for `length` frames. `offset` is
+// relative to the attacker, with +x pointing the way they face
+#[derive(Deserialize)]
+pub struct MoveBox {
+    #[serde(default)]
+    pub start: u32,
+    pub length: u32,
+    pub width: Fixed,
+    pub height: Fixed,
+    pub offset: FixedVec2,
+}
+
+// the frame data of a single move, as written by the designers in
+// assets/fighter.moves.ron
+#[derive(Deserialize)]
+pub struct MoveData {
+    pub startup: u32,
+    pub active: u32,
+    pub recovery: u32,
+    #[serde(default)]
+    pub hitboxes: Vec<MoveBox>,
+    #[serde(default)]
+    pub damage: Fixed,
+    #[serde(default)]
+    pub chip: Fixed,
+    #[serde(default)]
+    pub hitstun: u32,
+    #[serde(default)]
+    pub blockstun: u32,
+    #[serde(default)]
+    pub pushback: Fixed,
+    #[serde(default)]
+    pub level: HitLevel,
+    // a hit (not a block) knocks the defender up into the air
+    #[serde(default)]
+    pub launches: bool,
+    // moves with a motion come out when it is finished with an attack
+    // button, instead of through the normal button layout
+    #[serde(default)]
+    pub motion: Option<Motion>,
+}
+
+impl MoveData {
+    // how many frames the whole move lasts
+    pub fn total(&self) -> u32 {
+        self.startup + self.active + self.recovery
+    }
+}
+
+// all moves of a fighter, keyed by the state that performs them
+#[derive(Deserialize, TypeUuid)]
+#[serde(transparent)]
+#[uuid = "06d4ad05-1f86-444b-9387-0309f274b9ba"]
+pub struct MoveList(pub HashMap<PlayerState, MoveData>);
+
+impl MoveList {
+    // the move performed with `motion`. If several are, the one declared
+    // first in PlayerState wins, so every peer picks the same one no
+    // matter how the HashMap happens to be ordered
+    pub fn with_motion(&self, motion: Motion) -> Option<PlayerState> {
+        self.0
+            .iter()
+            .filter(|(_, data)| data.motion == Some(motion))
+            .map(|(&state, _)| state)
+            .min_by_key(|&state| state as u8)
+    }
+}
+
+// reads `*.moves.ron` files, so frame data can be tuned without
+// recompiling the game
+#[derive(Default)]
+pub struct MoveListLoader;
+
+impl AssetLoader for MoveListLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let moves = ron::de::from_bytes::<MoveList>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(moves));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["moves.ron"]
+    }
+}
+
+// throws out the hitboxes (or grabboxes, for the Grab state) of every
+// move that reaches the frame they come out on. Requested boxes only show
+// up on the next frame, so they are requested one frame early
+pub fn perform_moves(
+    move_assets: Res<MoveAssets>,
+    move_lists: Res<Assets<MoveList>>,
+    frame_count: Res<FrameCount>,
+    query: Query<(&Player, &FighterState)>,
+    mut hitbox_events: EventWriter<HitboxSpawnEvent>,
+    mut grabbox_events: EventWriter<GrabboxSpawnEvent>,
+) {
+    let Some(moves) = move_lists.get(&move_assets.moves) else {
+        return;
+    };
+
+    // go through the players in handle order so the boxes get their
+    // spawn ids in the same order on both peers
+    let mut players: Vec<_> = query.iter().collect();
+    players.sort_by_key(|(player, _)| player.handle);
+
+    for (player, fighter) in players {
+        let Some(data) = moves.0.get(&fighter.state) else {
+            continue;
+        };
+
+        for rect in &data.hitboxes {
+            if fighter.frame + 1 != data.startup + rect.start {
+                continue;
+            }
+            if fighter.state == PlayerState::Grab {
+                grabbox_events.send(GrabboxSpawnEvent {
+                    owner: player.handle,
+                    width: rect.width,
+                    height: rect.height,
+                    offset: rect.offset,
+                    active: rect.length,
+                });
+            } else {
+                hitbox_events.send(HitboxSpawnEvent {
+                    owner: player.handle,
+                    width: rect.width,
+                    height: rect.height,
+                    offset: rect.offset,
+                    damage: data.damage,
+                    chip: data.chip,
+                    hitstun: data.hitstun,
+                    blockstun: data.blockstun,
+                    pushback: data.pushback,
+                    level: data.level,
+                    launches: data.launches,
+                    active: rect.length,
+                    activation: frame_count.0 - fighter.frame as i32,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fighter_moves_load() {
+        let moves = ron::de::from_bytes::<MoveList>(include_bytes!("../assets/fighter.moves.ron")).unwrap();
+        let kick = &moves.0[&PlayerState::KickH];
+        assert_eq!((kick.hitstun, kick.blockstun), (22, 15));
+        assert_eq!(kick.pushback, Fixed::milli(500));
+        assert_eq!(moves.with_motion(Motion::QuarterCircleForward), Some(PlayerState::Special));
+    }
+}
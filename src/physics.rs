@@ -0,0 +1,367 @@
+use std::cmp::Ordering;
+
+use bevy::prelude::*;
+
+use crate::components::*;
+use crate::fixed::*;
+use crate::hitboxes::ShowBoxes;
+use crate::stage::{FLOOR_Y, WALL_X};
+
+// A small physics step of our own instead of rapier. Everything it keeps
+// lives in rollback components and it runs inside the rollback schedule,
+// so resimulated frames and the other peer come to exactly the same
+// result. Speeds are in units per simulation frame, and all of it is
+// fixed-point math (see fixed.rs).
+
+// how much upward speed a body loses every frame in the air. With the
+// default jump_impulse a jump lasts 40 frames and peaks 3 units up
+pub const GRAVITY: Fixed = Fixed::milli(15);
+
+// Where something is in the simulation. The Transform of the entity only
+// follows it for drawing (see sync_transforms), the simulation itself
+// never reads the floats back
+#[derive(Component, Reflect, Default, Clone, Copy)]
+pub struct Position(pub FixedVec2);
+
+impl Position {
+    pub fn new(x: Fixed, y: Fixed) -> Self {
+        Position(FixedVec2::new(x, y))
+    }
+}
+
+// speed and gravity of something that moves on the stage. `friction` is
+// the share of its sideways speed a body keeps every frame it spends on
+// the ground, so a push slides out instead of stopping dead
+#[derive(Component, Reflect, Default)]
+pub struct Body {
+    pub velocity: FixedVec2,
+    pub gravity_scale: Fixed,
+    pub friction: Fixed,
+    pub grounded: bool,
+}
+
+impl Body {
+    // a body standing on the floor
+    pub fn grounded(friction: Fixed) -> Self {
+        Self {
+            velocity: FixedVec2::ZERO,
+            gravity_scale: Fixed::ONE,
+            friction,
+            grounded: true,
+        }
+    }
+
+    // give the body a speed that slides it `distance` units along x before
+    // friction stops it
+    pub fn slide(&mut self, distance: Fixed) {
+        self.velocity.x = distance * (Fixed::ONE - self.friction);
+    }
+}
+
+// the box fighters push each other away with. Unlike the hurtbox it only
+// keeps fighters from standing inside each other
+#[derive(Component, Reflect, Default, Clone, Copy)]
+pub struct Pushbox {
+    pub width: Fixed,
+    pub height: Fixed,
+    pub offset: FixedVec2,
+}
+
+// The pushbox for each stance. Crouching fighters are lower and a bit
+// wider. Airborne ones are short and sit high, so a jump clears the
+// opponent's head and can cross over to the other side
+pub const STANDING_PUSHBOX: Pushbox = Pushbox {
+    width: Fixed::milli(800),
+    height: Fixed::ONE,
+    offset: FixedVec2::ZERO,
+};
+const CROUCHING_PUSHBOX: Pushbox = Pushbox {
+    width: Fixed::milli(900),
+    height: Fixed::milli(600),
+    offset: FixedVec2::new(Fixed::ZERO, Fixed::milli(-200)),
+};
+const AIRBORNE_PUSHBOX: Pushbox = Pushbox {
+    width: Fixed::milli(600),
+    height: Fixed::milli(600),
+    offset: FixedVec2::new(Fixed::ZERO, Fixed::milli(200)),
+};
+
+// marks the sprite that shows a fighter's pushbox while testing
+#[derive(Component)]
+pub struct PushboxSprite;
+
+// how far a body's center has to stay away from the walls
+pub fn wall_limit(pushbox: Option<&Pushbox>) -> Fixed {
+    WALL_X - pushbox.map_or(Fixed::ZERO, |pushbox| pushbox.width / 2 + pushbox.offset.x.abs())
+}
+
+// moves every body by its velocity, applies gravity in the air, and stops
+// bodies at the floor and the walls. Friction only comes after the move,
+// so a speed set for this frame (a walk, a dash, the first frame of a
+// push) is covered in full
+pub fn step_bodies(mut query: Query<(&mut Position, &mut Body, Option<&Pushbox>)>) {
+    for (mut position, mut body, pushbox) in query.iter_mut() {
+        if !body.grounded {
+            body.velocity.y = body.velocity.y - GRAVITY * body.gravity_scale;
+        }
+
+        let moved = position.0 + body.velocity;
+        let limit = wall_limit(pushbox);
+        position.0.x = moved.x.clamp(-limit, limit);
+        position.0.y = moved.y;
+        if moved.x.abs() > limit {
+            body.velocity.x = Fixed::ZERO;
+        }
+
+        if position.0.y <= FLOOR_Y {
+            position.0.y = FLOOR_Y;
+            if !body.grounded {
+                // bodies stop where they land
+                body.velocity = FixedVec2::ZERO;
+                body.grounded = true;
+            }
+        }
+
+        if body.grounded {
+            body.velocity.x = body.velocity.x * body.friction;
+        }
+    }
+}
+
+// picks the pushbox that goes with the stance the fighter is in now
+pub fn update_pushboxes(mut query: Query<(&FighterState, &mut Pushbox)>) {
+    for (fighter, mut pushbox) in query.iter_mut() {
+        *pushbox = if fighter.is_airborne() {
+            AIRBORNE_PUSHBOX
+        } else if fighter.is_crouching() {
+            CROUCHING_PUSHBOX
+        } else {
+            STANDING_PUSHBOX
+        };
+    }
+}
+
+type PushedFighter<'a> = (Mut<'a, Position>, &'a Pushbox, &'a Player);
+
+// Whether `a` is the one that goes left when it overlaps `b`. That's the
+// one further left, and while they are right on top of each other (like
+// in the middle of a jump over) the one facing right, since fighters in
+// the air keep facing the side they came from
+fn pushed_left(a: &PushedFighter, b: &PushedFighter) -> bool {
+    let (a_position, a_box, a_player) = a;
+    let (b_position, b_box, b_player) = b;
+    let a_x = a_position.0.x + a_box.offset.x;
+    let b_x = b_position.0.x + b_box.offset.x;
+    match a_x.cmp(&b_x) {
+        Ordering::Less => true,
+        Ordering::Greater => false,
+        Ordering::Equal => {
+            a_player.facing_direction == GameDirection::Right || b_player.facing_direction == GameDirection::Left
+        }
+    }
+}
+
+// Pushes two overlapping fighters apart. Each gives way by half the
+// overlap, except that a fighter backed into a wall can't, and then the
+// other one takes all of it. That's what keeps a cornered fighter in the
+// corner instead of letting them push their way out
+fn push_apart<'a>(a: &mut PushedFighter<'a>, b: &mut PushedFighter<'a>) {
+    let a_center = a.0.0 + a.1.offset;
+    let b_center = b.0.0 + b.1.offset;
+    let distance = (a_center - b_center).abs();
+    let reach = (FixedVec2::new(a.1.width, a.1.height) + FixedVec2::new(b.1.width, b.1.height)) / 2;
+    if distance.x >= reach.x || distance.y >= reach.y {
+        return;
+    }
+    let overlap = reach.x - distance.x;
+
+    let (left, right) = if pushed_left(a, b) { (a, b) } else { (b, a) };
+    let (left_position, left_box, _) = left;
+    let (right_position, right_box, _) = right;
+    let left_limit = wall_limit(Some(left_box));
+    let right_limit = wall_limit(Some(right_box));
+    let left_start = left_position.0.x;
+    let right_start = right_position.0.x;
+
+    let mut left_x = (left_start - overlap / 2).max(-left_limit);
+    let right_x = (right_start + overlap - (left_start - left_x)).min(right_limit);
+    // the right one was cornered too, so the left one goes the rest of the way
+    let remaining = overlap - (left_start - left_x) - (right_x - right_start);
+    left_x = (left_x - remaining).max(-left_limit);
+
+    left_position.0.x = left_x;
+    right_position.0.x = right_x;
+}
+
+// keeps fighters from standing inside each other. Pairs go in handle
+// order, so every peer pushes the same way
+pub fn separate_pushboxes(mut query: Query<(&mut Position, &Pushbox, &Player)>) {
+    let mut fighters: Vec<PushedFighter> = query.iter_mut().collect();
+    fighters.sort_by_key(|(.., player)| player.handle);
+
+    for j in 1..fighters.len() {
+        let (before, after) = fighters.split_at_mut(j);
+        for a in before.iter_mut() {
+            push_apart(a, &mut after[0]);
+        }
+    }
+}
+
+// a translucent child sprite of a fighter for its pushbox, kept in shape
+// by draw_pushboxes
+pub fn pushbox_sprite() -> (SpriteBundle, PushboxSprite) {
+    let sprite = SpriteBundle {
+        transform: Transform::from_xyz(0., 0., 1.),
+        sprite: Sprite {
+            color: Color::rgba(0., 1., 0., 0.3),
+            ..default()
+        },
+        ..default()
+    };
+    (sprite, PushboxSprite)
+}
+
+// outside of the simulation: shows every fighter's current pushbox while
+// the boxes are turned on (see ShowBoxes)
+pub fn draw_pushboxes(
+    show: Res<ShowBoxes>,
+    fighters: Query<(&Pushbox, &Children)>,
+    mut sprites: Query<(&mut Transform, &mut Sprite, &mut Visibility), With<PushboxSprite>>,
+) {
+    for (pushbox, children) in fighters.iter() {
+        for &child in children.iter() {
+            if let Ok((mut transform, mut sprite, mut visibility)) = sprites.get_mut(child) {
+                visibility.is_visible = show.0;
+                let offset = pushbox.offset.to_vec2();
+                transform.translation.x = offset.x;
+                transform.translation.y = offset.y;
+                sprite.custom_size = Some(Vec2::new(pushbox.width.to_f32(), pushbox.height.to_f32()));
+            }
+        }
+    }
+}
+
+// the last thing every simulation frame does: move the sprites to where
+// the simulation put things
+pub fn sync_transforms(mut query: Query<(&Position, &mut Transform)>) {
+    for (position, mut transform) in query.iter_mut() {
+        let position = position.0.to_vec2();
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use GameDirection::*;
+
+    fn fighter(world: &mut World, handle: usize, x: Fixed, facing_direction: GameDirection) {
+        world.spawn((
+            Position::new(x, FLOOR_Y),
+            STANDING_PUSHBOX,
+            Player {
+                handle,
+                facing_direction,
+                ..default()
+            },
+        ));
+    }
+
+    // runs separate_pushboxes on two standing fighters and returns where
+    // they end up, in handle order
+    fn separate(a: (Fixed, GameDirection), b: (Fixed, GameDirection)) -> (Fixed, Fixed) {
+        let mut world = World::new();
+        fighter(&mut world, 0, a.0, a.1);
+        fighter(&mut world, 1, b.0, b.1);
+        SystemStage::single(separate_pushboxes).run(&mut world);
+
+        let mut positions: Vec<_> = world.query::<(&Position, &Player)>().iter(&world).map(|(position, player)| (player.handle, position.0.x)).collect();
+        positions.sort();
+        (positions[0].1, positions[1].1)
+    }
+
+    fn goes_left(a: (Fixed, GameDirection), b: (Fixed, GameDirection)) -> bool {
+        let mut world = World::new();
+        fighter(&mut world, 0, a.0, a.1);
+        fighter(&mut world, 1, b.0, b.1);
+
+        let mut query = world.query::<(&mut Position, &Pushbox, &Player)>();
+        let mut fighters: Vec<PushedFighter> = query.iter_mut(&mut world).collect();
+        fighters.sort_by_key(|(.., player)| player.handle);
+        pushed_left(&fighters[0], &fighters[1])
+    }
+
+    // standing pushboxes are 0.8 wide, so fighters closer than that overlap
+    const LIMIT: Fixed = Fixed::milli(11600);
+
+    #[test]
+    fn apart_fighters_stay_put() {
+        let (a, b) = separate((Fixed::ZERO, Right), (Fixed::ONE, Left));
+        assert_eq!((a, b), (Fixed::ZERO, Fixed::ONE));
+    }
+
+    #[test]
+    fn overlap_is_split_evenly() {
+        let (a, b) = separate((Fixed::milli(-200), Right), (Fixed::milli(200), Left));
+        assert_eq!((a, b), (Fixed::milli(-400), Fixed::milli(400)));
+        // whatever order the handles are in
+        let (a, b) = separate((Fixed::milli(200), Left), (Fixed::milli(-200), Right));
+        assert_eq!((a, b), (Fixed::milli(400), Fixed::milli(-400)));
+    }
+
+    #[test]
+    fn cornered_fighter_stays_in_the_corner() {
+        let (a, b) = separate((-LIMIT, Right), (-LIMIT + Fixed::milli(400), Left));
+        assert_eq!((a, b), (-LIMIT, -LIMIT + Fixed::milli(800)));
+    }
+
+    #[test]
+    fn cornered_on_the_right_pushes_the_left_one_all_the_way() {
+        let (a, b) = separate((LIMIT - Fixed::milli(400), Right), (LIMIT, Left));
+        assert_eq!((a, b), (LIMIT - Fixed::milli(800), LIMIT));
+    }
+
+    #[test]
+    fn fighters_close_to_the_corner_share_what_room_there_is() {
+        // the left one only has 0.1 left before the wall, the right one
+        // goes the other 0.3
+        let (a, b) = separate((-LIMIT + Fixed::milli(100), Right), (-LIMIT + Fixed::milli(500), Left));
+        assert_eq!((a, b), (-LIMIT, -LIMIT + Fixed::milli(800)));
+    }
+
+    #[test]
+    fn nobody_leaves_the_stage() {
+        // right on top of each other in the corner, as after a throw
+        let (a, b) = separate((-LIMIT, Left), (-LIMIT, Right));
+        assert!(a >= -LIMIT && b >= -LIMIT);
+        assert!((a - b).abs() >= STANDING_PUSHBOX.width);
+
+        let (a, b) = separate((LIMIT, Left), (LIMIT, Right));
+        assert!(a <= LIMIT && b <= LIMIT);
+        assert!((a - b).abs() >= STANDING_PUSHBOX.width);
+    }
+
+    #[test]
+    fn further_left_goes_left() {
+        assert!(goes_left((Fixed::ZERO, Left), (Fixed::milli(1), Right)));
+        assert!(!goes_left((Fixed::milli(1), Right), (Fixed::ZERO, Left)));
+    }
+
+    #[test]
+    fn on_top_of_each_other_the_one_facing_right_goes_left() {
+        assert!(goes_left((Fixed::ZERO, Right), (Fixed::ZERO, Left)));
+        assert!(!goes_left((Fixed::ZERO, Left), (Fixed::ZERO, Right)));
+        // facing the same way, the first of the pair goes left. Pairs go in
+        // handle order, so every peer still picks the same one
+        assert!(goes_left((Fixed::ZERO, Right), (Fixed::ZERO, Right)));
+        assert!(goes_left((Fixed::ZERO, Left), (Fixed::ZERO, Left)));
+    }
+
+    #[test]
+    fn tie_break_pushes_them_apart() {
+        let (a, b) = separate((Fixed::ZERO, Left), (Fixed::ZERO, Right));
+        assert_eq!((a, b), (Fixed::milli(400), Fixed::milli(-400)));
+    }
+}
@@ -0,0 +1,151 @@
+use bevy::{prelude::*, render::camera::ScalingMode};
+
+use crate::components::*;
+use crate::fixed::*;
+use crate::physics::{wall_limit, Body, Position, Pushbox};
+
+// The stage is seen from the side: a floor the fighters stand on and a
+// wall on either end. Everything here that the simulation uses is
+// fixed-point, the camera only looks at the result.
+
+// height of a standing fighter's center above the stage floor line
+pub const FLOOR_Y: Fixed = Fixed::ZERO;
+// the walls sit this far left and right of the middle of the stage
+pub const WALL_X: Fixed = Fixed::from_int(12);
+// fighters can't get further apart than this, so both always fit on screen
+const MAX_DISTANCE: Fixed = Fixed::from_int(9);
+
+// the floor line in world units, where the fighters' feet are
+const FLOOR_LINE: f32 = -0.5;
+// how much of the stage is visible around the fighters, and how far the
+// camera can zoom in or out
+const CAMERA_MARGIN: f32 = 3.;
+const CAMERA_MIN_WIDTH: f32 = 8.;
+const CAMERA_MAX_WIDTH: f32 = 12.;
+// how far below the bottom of the screen the floor line stays
+const CAMERA_FLOOR_MARGIN: f32 = 1.5;
+
+// the floor and the walls, drawn once at startup
+pub fn spawn_stage(mut commands: Commands) {
+    let wall_x = WALL_X.to_f32();
+    let floor_color = Color::rgb(0.35, 0.3, 0.25);
+    let wall_color = Color::rgb(0.27, 0.27, 0.27);
+
+    // the ground, its top edge is the floor line
+    commands.spawn(SpriteBundle {
+        transform: Transform::from_xyz(0., FLOOR_LINE - 5., 0.),
+        sprite: Sprite {
+            color: floor_color,
+            custom_size: Some(Vec2::new(2. * wall_x + 2., 10.)),
+            ..default()
+        },
+        ..default()
+    });
+
+    for side in [-1., 1.] {
+        commands.spawn(SpriteBundle {
+            transform: Transform::from_xyz(side * (wall_x + 0.5), FLOOR_LINE + 10., 0.),
+            sprite: Sprite {
+                color: wall_color,
+                custom_size: Some(Vec2::new(1., 20.)),
+                ..default()
+            },
+            ..default()
+        });
+    }
+
+    let mut camera_bundle = Camera2dBundle::default();
+    // the camera zooms by changing its scale, see frame_fighters
+    camera_bundle.projection.scaling_mode = ScalingMode::FixedHorizontal(CAMERA_MIN_WIDTH);
+    commands.spawn(camera_bundle);
+}
+
+// how fast a fighter bounces back off the wall, and how high
+const WALLBOUNCE_SPEED: Fixed = Fixed::milli(60);
+const WALLBOUNCE_LIFT: Fixed = Fixed::milli(150);
+
+// Fighters knocked through the air (launched or hit while airborne) that
+// step_bodies stopped at a wall bounce back off it, and get hit again on
+// the way down if the attacker is quick
+pub fn bounce_off_walls(mut query: Query<(&Position, &Pushbox, &mut Body, &mut FighterState)>) {
+    for (position, pushbox, mut body, mut fighter) in query.iter_mut() {
+        if body.grounded || !matches!(fighter.state, PlayerState::Launch | PlayerState::HitstunA) {
+            continue;
+        }
+        if position.0.x.abs() < wall_limit(Some(pushbox)) {
+            continue;
+        }
+        fighter.wallbounce();
+        body.velocity.x = WALLBOUNCE_SPEED * -position.0.x.signum();
+        body.velocity.y = body.velocity.y.max(WALLBOUNCE_LIFT);
+    }
+}
+
+// Keeps the fighters within MAX_DISTANCE of each other. A fighter that
+// walked away from the other this frame is held back; if both did (or
+// neither did, when something else pushed them apart) they are pulled
+// back by half each. Pairs go in handle order like the pushboxes do
+pub fn limit_distance(mut query: Query<(&mut Position, &Body, &Player)>) {
+    let mut fighters: Vec<_> = query.iter_mut().collect();
+    fighters.sort_by_key(|(.., player)| player.handle);
+
+    for j in 1..fighters.len() {
+        let (before, after) = fighters.split_at_mut(j);
+        let (b_position, b_body, _) = &mut after[0];
+        for (a_position, a_body, _) in before.iter_mut() {
+            let distance = (a_position.0.x - b_position.0.x).abs();
+            if distance <= MAX_DISTANCE {
+                continue;
+            }
+            let excess = distance - MAX_DISTANCE;
+
+            // +1 if `a` is the one on the right
+            let a_side = (a_position.0.x - b_position.0.x).signum();
+            let a_away = a_body.velocity.x.signum() == a_side;
+            let b_away = b_body.velocity.x.signum() == -a_side;
+            let a_share = match (a_away, b_away) {
+                (true, false) => excess,
+                (false, true) => Fixed::ZERO,
+                _ => excess / 2,
+            };
+            a_position.0.x -= a_share * a_side;
+            b_position.0.x += (excess - a_share) * a_side;
+        }
+    }
+}
+
+type CameraQuery<'w, 's> =
+    Query<'w, 's, (&'static mut Transform, &'static mut OrthographicProjection), (With<Camera>, Without<Player>)>;
+
+// Pans and zooms the camera so it frames all fighters, without looking
+// past the walls. It only reads where the simulation put the fighters,
+// so both peers see the same picture
+pub fn frame_fighters(
+    windows: Res<Windows>,
+    fighters: Query<&Transform, With<Player>>,
+    mut cameras: CameraQuery,
+) {
+    let mut xs = fighters.iter().map(|transform| transform.translation.x);
+    let Some(first) = xs.next() else {
+        return;
+    };
+    let (left, right) = xs.fold((first, first), |(left, right), x| (left.min(x), right.max(x)));
+
+    let wall_x = WALL_X.to_f32();
+    let width = (right - left + 2. * CAMERA_MARGIN).clamp(CAMERA_MIN_WIDTH, CAMERA_MAX_WIDTH);
+    let limit = (wall_x - width / 2.).max(0.);
+    let x = ((left + right) / 2.).clamp(-limit, limit);
+
+    // the floor line stays at the same spot near the bottom of the screen
+    let aspect = windows
+        .get_primary()
+        .map_or(16. / 9., |window| window.width() / window.height().max(1.));
+    let height = width / aspect;
+    let y = FLOOR_LINE - CAMERA_FLOOR_MARGIN + height / 2.;
+
+    for (mut transform, mut projection) in cameras.iter_mut() {
+        transform.translation.x = x;
+        transform.translation.y = y;
+        projection.scale = width / CAMERA_MIN_WIDTH;
+    }
+}
@@ -0,0 +1,491 @@
+use bevy::prelude::*;
+
+use crate::args::Args;
+use crate::components::*;
+use crate::fixed::*;
+use crate::input::*;
+use crate::motions::*;
+use crate::moves::MoveList;
+use crate::physics::{wall_limit, Position, Pushbox};
+use crate::MoveAssets;
+
+use PlayerState::*;
+
+// pressing a button from this knockdown frame on turns it into a quick rise
+const QUICK_RISE_FRAME: u32 = 10;
+// a thrown fighter can break the throw by pressing grab during its first
+// few frames
+const THROW_TECH_WINDOW: u32 = 7;
+// how far from the thrower the thrown fighter lands
+const THROW_DISTANCE: Fixed = Fixed::ONE;
+
+// how many frames each fixed-length state lasts before the fighter moves
+// on. Attacks, grabs and throws take their length from the move data,
+// stun states last `FighterState::stun` frames, and the neutral states
+// last until the input changes
+fn duration(state: PlayerState, moves: &MoveList) -> u32 {
+    if let Some(data) = moves.0.get(&state) {
+        return data.total();
+    }
+    match state {
+        PreJump => 4,
+        Landing => 4,
+        Dash => 16,
+        DashB => 18,
+        Launch => 30,
+        Wallbounce => 24,
+        Knockdown => 40,
+        Wakeup => 20,
+        WakeupQ => 10,
+        _ => 0,
+    }
+}
+
+// +1 when the fighter faces right, -1 when it faces left
+pub fn forward(facing: GameDirection) -> i8 {
+    match facing {
+        GameDirection::Left => -1,
+        GameDirection::Right => 1,
+    }
+}
+
+impl FighterState {
+    // switch to a new state and restart its frame counter
+    pub fn enter(&mut self, state: PlayerState) {
+        self.state = state;
+        self.frame = 0;
+    }
+
+    pub fn is_airborne(&self) -> bool {
+        self.air_frames > 0
+    }
+
+    pub fn is_crouching(&self) -> bool {
+        matches!(
+            self.state,
+            Crouch | BlockC | HitstunC | PunchLC | PunchHC | KickLC | KickHC
+        )
+    }
+
+    // idle, walking and crouching fighters are free to start something new
+    pub fn is_neutral(&self) -> bool {
+        matches!(self.state, Idle | Walk | WalkB | Crouch)
+    }
+
+    // fighters on the ground who aren't reeling from a hit, knocked down
+    // or already in a throw can be grabbed. Blocking doesn't help
+    pub fn is_throwable(&self) -> bool {
+        !self.is_airborne()
+            && !self.in_throw()
+            && !matches!(
+                self.state,
+                Hitstun | HitstunC | HitstunA | Launch | Falldown | Knockdown | Wallbounce | Wakeup | WakeupQ | Defeat
+            )
+    }
+
+    // both fighters are locked in place until a throw is over
+    pub fn in_throw(&self) -> bool {
+        matches!(self.state, ThrowF | ThrowB | Thrown)
+    }
+
+    // Fighters only turn around while they are free on the ground. In the
+    // middle of an attack or a jump they keep facing the way they started,
+    // even if the opponent crosses over them
+    pub fn can_turn(&self) -> bool {
+        self.is_neutral() && !self.is_airborne()
+    }
+
+    // put the fighter into hitstun for `stun` frames, using the variant
+    // that matches its current stance
+    pub fn hit(&mut self, stun: u32) {
+        let state = if self.is_airborne() {
+            HitstunA
+        } else if self.is_crouching() {
+            HitstunC
+        } else {
+            Hitstun
+        };
+        self.enter(state);
+        self.stun = stun;
+    }
+
+    // put the fighter into blockstun for `stun` frames, guarding in the
+    // given stance
+    pub fn block(&mut self, stun: u32, crouching: bool) {
+        let state = if crouching { BlockC } else { Block };
+        self.enter(state);
+        self.stun = stun;
+    }
+
+    // knocked up into the air by a launcher (see resolve_hits)
+    pub fn launch(&mut self) {
+        self.enter(Launch);
+        self.air_frames = self.air_frames.max(1);
+    }
+
+    // knocked into a wall while in the air (see stage::bounce_off_walls)
+    pub fn wallbounce(&mut self) {
+        self.enter(Wallbounce);
+        self.air_frames = self.air_frames.max(1);
+    }
+
+    // a connected grab turns into a forward or backward throw of the
+    // fighter with handle `partner`
+    pub fn throw(&mut self, forward: bool, partner: usize) {
+        self.enter(if forward { ThrowF } else { ThrowB });
+        self.partner = partner;
+    }
+
+    // held by the throw of the fighter with handle `partner`
+    pub fn thrown(&mut self, partner: usize) {
+        self.enter(Thrown);
+        self.partner = partner;
+    }
+
+    // go back to neutral on the ground
+    fn recover(&mut self, input: PlayerInput) {
+        self.air_frames = 0;
+        self.enter(if input.down() { Crouch } else { Idle });
+    }
+
+    // Called by apply_jumps when an airborne fighter touches the floor.
+    // Jumps and air attacks end in a few frames of landing recovery, a
+    // fighter knocked out of the air ends up on the ground
+    pub fn land(&mut self) {
+        self.air_frames = 0;
+        match self.state {
+            Jump | PunchLA | PunchHA | KickLA | KickHA | Block | BlockC => self.enter(Landing),
+            HitstunA | Launch | Falldown | Wallbounce => self.enter(Knockdown),
+            _ => {}
+        }
+    }
+
+    // a double tap starts a dash, forward or backward
+    fn dash(&self, controls: &Controls, forward: i8, windows: &MotionWindows) -> Option<PlayerState> {
+        if windows.performed(Motion::DashForward, controls, self, forward) {
+            Some(Dash)
+        } else if windows.performed(Motion::DashBack, controls, self, forward) {
+            Some(DashB)
+        } else {
+            None
+        }
+    }
+
+    // the special move whose motion was just finished with an attack
+    // button, if the fighter has one for it
+    fn special(
+        &mut self,
+        controls: &Controls,
+        pressed: PlayerInput,
+        forward: i8,
+        moves: &MoveList,
+        windows: &MotionWindows,
+    ) -> Option<PlayerState> {
+        if !pressed.any_attack() {
+            return None;
+        }
+        let motion = MOTION_PRIORITY.into_iter().find(|&motion| {
+            moves.with_motion(motion).is_some() && windows.performed(motion, controls, self, forward)
+        })?;
+        if motion == Motion::Charge {
+            self.charge_window = 0; // the charge is used up
+        }
+        moves.with_motion(motion)
+    }
+
+    // advance the state machine by one simulation frame
+    fn step(
+        &mut self,
+        controls: &mut Controls,
+        forward: i8,
+        defeated: bool,
+        moves: &MoveList,
+        leniency: usize,
+        windows: &MotionWindows,
+    ) {
+        let input = controls.current();
+        // presses from the last few frames count as well, so a button
+        // pressed just before the fighter recovers isn't lost
+        let pressed = controls.buffered(leniency);
+        let previous = self.state;
+        self.frame += 1;
+        if self.is_airborne() {
+            self.air_frames += 1;
+        }
+        self.track_charge(input, forward, windows);
+
+        if defeated {
+            if self.state != Defeat {
+                self.enter(Defeat);
+            }
+            return;
+        }
+
+        match self.state {
+            // resolve_throws lets go of a thrown fighter
+            Defeat | Thrown => {}
+            Idle | Walk | WalkB | Crouch => {
+                let next = match self.special(controls, pressed, forward, moves, windows) {
+                    Some(special) => special,
+                    None => neutral_state(input, pressed, forward, self.dash(controls, forward, windows)),
+                };
+                if next != self.state {
+                    self.enter(next);
+                }
+            }
+            // apply_jumps takes off with the Jump state and ends it on
+            // landing. Specials still come out until then, since motions
+            // like the 360 pass through up on the way
+            PreJump => {
+                if let Some(special) = self.special(controls, pressed, forward, moves, windows) {
+                    self.enter(special);
+                } else if self.frame >= duration(PreJump, moves) {
+                    self.air_frames = 1;
+                    self.enter(Jump);
+                }
+            }
+            Jump => {
+                if let Some(attack) = air_normal(pressed) {
+                    self.enter(attack);
+                }
+            }
+            PunchLA | PunchHA | KickLA | KickHA => {
+                if self.frame >= duration(self.state, moves) {
+                    self.enter(Jump);
+                }
+            }
+            Hitstun | HitstunC => {
+                if self.frame >= self.stun {
+                    self.recover(input);
+                }
+            }
+            Block | BlockC => {
+                if self.frame >= self.stun {
+                    // an air block falls on as a normal jump
+                    if self.is_airborne() {
+                        self.enter(Jump);
+                    } else {
+                        self.recover(input);
+                    }
+                }
+            }
+            HitstunA => {
+                if self.frame >= self.stun {
+                    self.enter(Falldown);
+                }
+            }
+            Launch | Wallbounce => {
+                if self.frame >= duration(self.state, moves) {
+                    self.enter(Falldown);
+                }
+            }
+            // falls until apply_jumps lands it
+            Falldown => {}
+            Knockdown => {
+                if self.frame >= QUICK_RISE_FRAME && pressed.any_attack() {
+                    self.enter(WakeupQ);
+                } else if self.frame >= duration(Knockdown, moves) {
+                    self.enter(Wakeup);
+                }
+            }
+            // dashes, wakeups, grounded attacks, grabs and throws all run
+            // for a fixed number of frames
+            _ => {
+                if self.frame >= duration(self.state, moves) {
+                    self.recover(input);
+                }
+            }
+        }
+
+        // whatever the buffered presses started has come out now, they
+        // mustn't start it again
+        let from_buttons = moves.0.contains_key(&self.state) || self.state == WakeupQ;
+        if self.state != previous && from_buttons {
+            controls.consume(pressed);
+        }
+    }
+}
+
+// the state a free fighter on the ground picks for this frame's input.
+// Moves come out on button presses, movement follows the held directions
+fn neutral_state(input: PlayerInput, pressed: PlayerInput, forward: i8, dash: Option<PlayerState>) -> PlayerState {
+    let crouching = input.down();
+
+    if pressed.grab() && !crouching {
+        return Grab;
+    }
+    if pressed.heavykick() && input.holds_forward(forward) && !crouching {
+        return Command;
+    }
+    if let Some(attack) = ground_normal(pressed, crouching) {
+        return attack;
+    }
+    if input.up() {
+        return PreJump;
+    }
+    if crouching {
+        return Crouch;
+    }
+    if let Some(dash) = dash {
+        return dash;
+    }
+    if input.holds_forward(forward) {
+        Walk
+    } else if input.holds_back(forward) {
+        WalkB
+    } else {
+        Idle
+    }
+}
+
+fn ground_normal(pressed: PlayerInput, crouching: bool) -> Option<PlayerState> {
+    let attack = if pressed.heavykick() {
+        if crouching { KickHC } else { KickH }
+    } else if pressed.heavypunch() {
+        if crouching { PunchHC } else { PunchH }
+    } else if pressed.lightkick() {
+        if crouching { KickLC } else { KickL }
+    } else if pressed.lightpunch() {
+        if crouching { PunchLC } else { PunchL }
+    } else {
+        return None;
+    };
+    Some(attack)
+}
+
+fn air_normal(pressed: PlayerInput) -> Option<PlayerState> {
+    if pressed.heavykick() {
+        Some(KickHA)
+    } else if pressed.heavypunch() {
+        Some(PunchHA)
+    } else if pressed.lightkick() {
+        Some(KickLA)
+    } else if pressed.lightpunch() {
+        Some(PunchLA)
+    } else {
+        None
+    }
+}
+
+// Turns every fighter that is allowed to (see can_turn) toward the
+// closest opponent, so forward and back follow them when they switch
+// sides. Runs before the state machines, which read forward and back
+pub fn face_opponents(mut query: Query<(&Position, &mut Player, &FighterState)>) {
+    let positions: Vec<(usize, Fixed)> = query
+        .iter()
+        .map(|(position, player, _)| (player.handle, position.0.x))
+        .collect();
+
+    for (position, mut player, fighter) in query.iter_mut() {
+        if !fighter.can_turn() {
+            continue;
+        }
+        let x = position.0.x;
+        // ties go to the lower handle, so every peer picks the same one
+        let opponent = positions
+            .iter()
+            .filter(|(handle, _)| *handle != player.handle)
+            .min_by_key(|(handle, opponent_x)| ((*opponent_x - x).abs(), *handle));
+        let Some(&(_, opponent_x)) = opponent else {
+            continue;
+        };
+
+        let facing = if opponent_x > x {
+            GameDirection::Right
+        } else if opponent_x < x {
+            GameDirection::Left
+        } else {
+            continue; // right on top of each other, keep facing the same way
+        };
+        if player.facing_direction != facing {
+            player.facing_direction = facing;
+        }
+    }
+}
+
+// drives every fighter's state machine from the confirmed / predicted
+// inputs (see update_controls), so it has to run inside the rollback schedule
+pub fn update_fighter_states(
+    move_assets: Res<MoveAssets>,
+    move_lists: Res<Assets<MoveList>>,
+    args: Res<Args>,
+    windows: Res<MotionWindows>,
+    mut query: Query<(&Player, &mut Controls, &mut FighterState)>,
+) {
+    let Some(moves) = move_lists.get(&move_assets.moves) else {
+        return;
+    };
+
+    for (player, mut controls, mut fighter) in query.iter_mut() {
+        let forward = forward(player.facing_direction);
+        fighter.step(&mut controls, forward, player.hp <= Fixed::ZERO, moves, args.input_buffer, &windows);
+    }
+}
+
+// Plays out the throws started by resolve_grabs. The thrown fighter can
+// tech the throw by pressing grab within THROW_TECH_WINDOW frames, and
+// then both fighters hop back out of it. Otherwise the throw lands on its
+// first active frame: the damage is dealt and the thrown fighter is put
+// down in front of the thrower, or behind them for a back throw. Where
+// that would be past a wall, the thrown fighter lands against it and the
+// thrower steps out of the corner instead, so a back throw still swaps
+// sides
+pub fn resolve_throws(
+    move_assets: Res<MoveAssets>,
+    move_lists: Res<Assets<MoveList>>,
+    args: Res<Args>,
+    mut query: Query<(Entity, &mut Position, &Pushbox, &mut Controls, &mut Player, &mut FighterState)>,
+) {
+    let Some(moves) = move_lists.get(&move_assets.moves) else {
+        return;
+    };
+
+    // handle order, so every peer resolves the throws the same way
+    let mut thrown: Vec<(usize, Entity, usize)> = query
+        .iter()
+        .filter(|(.., fighter)| fighter.state == Thrown)
+        .map(|(entity, _, _, _, player, fighter)| (player.handle, entity, fighter.partner))
+        .collect();
+    thrown.sort_by_key(|(handle, ..)| *handle);
+
+    for (_, defender_entity, partner) in thrown {
+        let thrower = query
+            .iter()
+            .find(|(.., player, fighter)| player.handle == partner && matches!(fighter.state, ThrowF | ThrowB))
+            .map(|(entity, ..)| entity);
+
+        let Some(attacker_entity) = thrower else {
+            // the thrower got interrupted somehow, let go
+            let (.., controls, _, mut defender) = query.get_mut(defender_entity).unwrap();
+            defender.recover(controls.current());
+            continue;
+        };
+
+        let [attacker, defender] = query.get_many_mut([attacker_entity, defender_entity]).unwrap();
+        let (_, mut attacker_position, _, _, attacker_player, mut attacker) = attacker;
+        let (_, mut defender_position, defender_pushbox, mut defender_controls, mut defender_player, mut defender) = defender;
+
+        if defender.frame <= THROW_TECH_WINDOW {
+            let pressed = defender_controls.buffered(args.input_buffer);
+            if pressed.grab() {
+                defender_controls.consume(pressed);
+                attacker.enter(DashB);
+                defender.enter(DashB);
+                continue;
+            }
+        }
+
+        let Some(data) = moves.0.get(&attacker.state) else {
+            continue;
+        };
+        if attacker.frame == data.startup {
+            defender_player.hp = (defender_player.hp - data.damage).max(Fixed::ZERO);
+            let forward = forward(attacker_player.facing_direction) as i32;
+            let side = if attacker.state == ThrowF { forward } else { -forward };
+            let limit = wall_limit(Some(defender_pushbox));
+            defender_position.0.x = (attacker_position.0.x + THROW_DISTANCE * side).clamp(-limit, limit);
+            attacker_position.0.x = defender_position.0.x - THROW_DISTANCE * side;
+            defender.enter(Knockdown);
+        }
+    }
+}
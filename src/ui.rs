@@ -0,0 +1,25 @@
+use bevy::prelude::*;
+
+use crate::FontAssets;
+
+// a line of text pinned to the top left corner of the window, `top`
+// pixels down from the edge
+pub fn status_text(fonts: &FontAssets, text: impl Into<String>, color: Color, top: f32) -> TextBundle {
+    TextBundle::from_section(
+        text,
+        TextStyle {
+            font: fonts.font.clone(),
+            font_size: 32.,
+            color,
+        },
+    )
+    .with_style(Style {
+        position_type: PositionType::Absolute,
+        position: UiRect {
+            top: Val::Px(top),
+            left: Val::Px(10.),
+            ..default()
+        },
+        ..default()
+    })
+}